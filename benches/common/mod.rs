@@ -0,0 +1,57 @@
+//! Synthetic write patterns shared by the benchmarks
+
+use std::fmt;
+
+pub type Key = [u8; 16];
+pub type Value = [u8; 128];
+
+// Number of distinct keys written by the hot-key pattern
+const HOT_KEYS: u64 = 100;
+
+/// Key sequence of a workload
+#[derive(Debug, Clone, Copy)]
+pub enum Pattern {
+    /// Uniformly random keys, so every write is a fresh entry
+    Random,
+    /// Increasing keys, as written by an append-only log
+    Sequential,
+    /// Updates cycling over a few keys, which tombstoned CRDTs handle worst
+    HotKeys,
+}
+
+impl Pattern {
+    pub const ALL: [Pattern; 3] = [Pattern::Random, Pattern::Sequential, Pattern::HotKeys];
+
+    /// Returns the `n`th key written by the pattern
+    pub fn key(self, n: u64) -> Key {
+        let mut key = [0u8; 16];
+        match self {
+            Pattern::Random => rand::fill(&mut key),
+            Pattern::Sequential => key[8..].copy_from_slice(&n.to_be_bytes()),
+            Pattern::HotKeys => key[8..].copy_from_slice(&(n % HOT_KEYS).to_be_bytes()),
+        }
+        key
+    }
+
+    /// Returns the first `len` keys written by the pattern
+    pub fn keys(self, len: u64) -> Vec<Key> {
+        (0..len).map(|n| self.key(n)).collect()
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pattern::Random => write!(f, "random"),
+            Pattern::Sequential => write!(f, "sequential"),
+            Pattern::HotKeys => write!(f, "hot_keys"),
+        }
+    }
+}
+
+/// Returns a random value
+pub fn value() -> Value {
+    let mut value = [0u8; 128];
+    rand::fill(&mut value);
+    value
+}
@@ -0,0 +1,42 @@
+//! KVStore commit latency under random, sequential and hot-key write patterns
+
+mod common;
+
+use common::{Pattern, value};
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use cubby::kv::KVStore;
+
+// Entries written before measuring
+const ENTRIES: u64 = 10_000;
+
+fn bench_commit(c: &mut Criterion) {
+    let mut group = c.benchmark_group("kv_commit");
+    let value = value();
+    for pattern in Pattern::ALL {
+        for writes in [1, 100] {
+            let mut store = KVStore::open(&":memory:").unwrap();
+            let mut txn = store.begin().unwrap();
+            for key in pattern.keys(ENTRIES) {
+                txn.insert(&key, &value).unwrap();
+            }
+            txn.commit().unwrap();
+
+            let mut n = ENTRIES;
+            let id = format!("{pattern}/{writes}");
+            group.bench_function(BenchmarkId::new("commit", id), |bench| {
+                bench.iter(|| {
+                    let mut txn = store.begin().unwrap();
+                    for _ in 0..writes {
+                        txn.insert(&pattern.key(n), &value).unwrap();
+                        n += 1;
+                    }
+                    txn.commit().unwrap();
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_commit);
+criterion_main!(benches);
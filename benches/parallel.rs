@@ -0,0 +1,71 @@
+//! Parallel diff building and integration against the sequential versions,
+//! over the `examples/transaction.rs` workload and a store with many authors
+
+use std::hint::black_box;
+
+use criterion::{BatchSize, BenchmarkId, Criterion, criterion_group, criterion_main};
+use cubby::memory::MemStore;
+
+type Store = MemStore<[u8; 16], [u8; 128]>;
+
+// A store of `len` random entries, written in a single transaction by each of `authors`
+fn store(authors: usize, len: usize) -> Store {
+    let mut store = MemStore::new("hub");
+    for author in 0..authors {
+        let mut peer = MemStore::new(&format!("peer-{author}"));
+        let mut txn = peer.begin();
+        for _ in 0..len / authors {
+            let mut key = [0u8; 16];
+            let mut value = [0u8; 128];
+            rand::fill(&mut key);
+            rand::fill(&mut value);
+            txn.insert(key, value);
+        }
+        txn.commit();
+        store.integrate_diff(peer.build_diff(store.request_diff()));
+    }
+    store
+}
+
+fn bench_parallel(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parallel");
+    group.sample_size(10);
+    for (authors, len) in [(1, 200_000), (200, 200_000)] {
+        let a = store(authors, len);
+        let empty = Store::new("bob");
+        let id = format!("{authors}x{}", len / authors);
+
+        group.bench_function(BenchmarkId::new("build_diff", &id), |bench| {
+            bench.iter(|| black_box(a.build_diff(empty.request_diff())))
+        });
+        group.bench_function(BenchmarkId::new("build_diff_par", &id), |bench| {
+            bench.iter(|| black_box(a.build_diff_par(empty.request_diff())))
+        });
+
+        let diff = a.build_diff(empty.request_diff());
+        group.bench_function(BenchmarkId::new("integrate_diff", &id), |bench| {
+            bench.iter_batched(
+                || (Store::new("bob"), diff.clone()),
+                |(mut b, diff)| {
+                    b.integrate_diff(diff);
+                    b
+                },
+                BatchSize::LargeInput,
+            )
+        });
+        group.bench_function(BenchmarkId::new("integrate_diff_par", &id), |bench| {
+            bench.iter_batched(
+                || (Store::new("bob"), diff.clone()),
+                |(mut b, diff)| {
+                    b.integrate_diff_par(diff);
+                    b
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parallel);
+criterion_main!(benches);
@@ -0,0 +1,62 @@
+//! Hash-based reconciliation against the full-index diff request,
+//! for large stores with few differences
+
+use std::hint::black_box;
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use cubby::memory::MemStore;
+
+type Store = MemStore<u64, u64>;
+
+// Two synced stores of `len` entries, each with `changes` more local writes
+fn stores(len: u64, changes: u64) -> (Store, Store) {
+    let mut a = MemStore::new("alice");
+    let mut b = MemStore::new("bob");
+    for key in 0..len {
+        a.insert(key, key);
+    }
+    b.integrate_diff(a.build_diff(b.request_diff()));
+    for key in 0..changes {
+        a.insert(key * 7, 0);
+        b.insert(len + key, 0);
+    }
+    (a, b)
+}
+
+// Reconciles `to` against `from`, returning the bytes sent by `to`
+fn reconcile(from: &Store, to: &Store) -> usize {
+    let mut reconciler = to.reconciler();
+    let mut request = reconciler.request();
+    let mut bytes = request.hash_size();
+    while let Some(next) = reconciler.refine(from.compare_hashes(&request)) {
+        request = next;
+        bytes += request.hash_size();
+    }
+    let request = reconciler.finish();
+    bytes += request.index_size();
+    black_box(from.build_region_diff(request));
+    bytes
+}
+
+fn bench_reconcile(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reconcile");
+    for len in [10_000, 100_000] {
+        let (a, b) = stores(len, 10);
+        println!(
+            "{len} entries: full request {} bytes, hash reconciliation {} bytes",
+            a.request_diff().index_size(),
+            reconcile(&b, &a),
+        );
+
+        group.bench_with_input(BenchmarkId::new("full_request", len), &len, |bench, _| {
+            bench.iter(|| black_box(b.build_diff(a.request_diff())))
+        });
+        group.bench_with_input(BenchmarkId::new("hashes", len), &len, |bench, _| {
+            bench.iter(|| reconcile(&b, &a))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_reconcile);
+criterion_main!(benches);
@@ -0,0 +1,199 @@
+//! MemStore writes, diff requests, state sync and op sync
+//! under random, sequential and hot-key write patterns
+
+mod common;
+
+use std::hint::black_box;
+
+use common::{Key, Pattern, Value, value};
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use cubby::{diff::Diff, memory::MemStore};
+
+type Store = MemStore<Key, Value>;
+
+// Writes per write throughput sample
+const WRITES: u64 = 10_000;
+
+// Writes behind each request and diff
+const ENTRIES: u64 = 100_000;
+
+// Writes `keys` one by one
+fn store(name: &str, keys: &[Key]) -> Store {
+    let mut store = MemStore::new(name);
+    let value = value();
+    for key in keys {
+        store.insert(*key, value);
+    }
+    store
+}
+
+fn bench_writes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("writes");
+    group.throughput(Throughput::Elements(WRITES));
+    let value = value();
+    for pattern in Pattern::ALL {
+        let keys = pattern.keys(WRITES);
+        group.bench_function(BenchmarkId::new("insert", pattern), |bench| {
+            bench.iter_batched(
+                || Store::new("alice"),
+                |mut store| {
+                    for key in &keys {
+                        store.insert(*key, value);
+                    }
+                    store
+                },
+                BatchSize::LargeInput,
+            )
+        });
+        group.bench_function(BenchmarkId::new("remove", pattern), |bench| {
+            bench.iter_batched(
+                || store("alice", &keys),
+                |mut store| {
+                    for key in &keys {
+                        store.remove(key);
+                    }
+                    store
+                },
+                BatchSize::LargeInput,
+            )
+        });
+        group.bench_function(BenchmarkId::new("txn_commit", pattern), |bench| {
+            bench.iter_batched(
+                || Store::new("alice"),
+                |mut store| {
+                    let mut txn = store.begin();
+                    for key in &keys {
+                        txn.insert(*key, value);
+                    }
+                    txn.commit();
+                    store
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn bench_request_diff(c: &mut Criterion) {
+    let mut group = c.benchmark_group("request_diff");
+    for pattern in Pattern::ALL {
+        let mut a = store("alice", &pattern.keys(ENTRIES));
+        println!(
+            "{pattern}: {} entries, request {} bytes",
+            a.len(),
+            a.request_diff().index_size()
+        );
+
+        group.bench_function(BenchmarkId::new("unchanged", pattern), |bench| {
+            bench.iter(|| black_box(a.request_diff()))
+        });
+        let value = value();
+        let mut n = ENTRIES;
+        group.bench_function(BenchmarkId::new("after_write", pattern), |bench| {
+            bench.iter(|| {
+                a.insert(pattern.key(n), value);
+                n += 1;
+                black_box(a.request_diff())
+            })
+        });
+    }
+    group.finish();
+}
+
+// Stores where `to` holds the `overlap` share of the entries of `from`,
+// of which `from` has since deleted the `deletes` share, and the diff `from` sends to `to`
+struct DiffCase {
+    from: Store,
+    base: Option<Diff<Key, Value>>,
+    diff: Diff<Key, Value>,
+}
+
+impl DiffCase {
+    fn new(overlap: f64, deletes: f64) -> Self {
+        let keys = Pattern::Random.keys(ENTRIES);
+        let mut from = store("alice", &keys);
+        let shared = (ENTRIES as f64 * overlap) as usize;
+        let base = (shared > 0).then(|| {
+            let diff = from.build_diff(Store::new("bob").request_diff());
+            diff.split(shared).swap_remove(0)
+        });
+        for key in &keys[..(shared as f64 * deletes) as usize] {
+            from.remove(key);
+        }
+        let diff = from.build_diff(DiffCase::to(&base).request_diff());
+        DiffCase { from, base, diff }
+    }
+
+    fn to(base: &Option<Diff<Key, Value>>) -> Store {
+        let mut to = Store::new("bob");
+        if let Some(base) = base {
+            to.integrate_diff(base.clone());
+        }
+        to
+    }
+}
+
+fn bench_diff(c: &mut Criterion) {
+    let mut group = c.benchmark_group("diff");
+    group.sample_size(10);
+    for overlap in [0.0, 0.5, 0.9] {
+        for deletes in [0.0, 0.1, 0.5] {
+            if overlap == 0.0 && deletes > 0.0 {
+                continue;
+            }
+            let case = DiffCase::new(overlap, deletes);
+            let to = DiffCase::to(&case.base);
+            let id = format!("overlap_{overlap}/deletes_{deletes}");
+
+            group.bench_function(BenchmarkId::new("build_diff", &id), |bench| {
+                bench.iter(|| black_box(case.from.build_diff(to.request_diff())))
+            });
+            group.bench_function(BenchmarkId::new("integrate_diff", &id), |bench| {
+                bench.iter_batched(
+                    || (DiffCase::to(&case.base), case.diff.clone()),
+                    |(mut to, diff)| {
+                        to.integrate_diff(diff);
+                        to
+                    },
+                    BatchSize::LargeInput,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+fn bench_opset(c: &mut Criterion) {
+    let mut group = c.benchmark_group("opset");
+    group.throughput(Throughput::Elements(WRITES));
+    let value = value();
+    for pattern in Pattern::ALL {
+        let mut a = Store::new("alice").with_opset();
+        for key in pattern.keys(WRITES) {
+            a.insert(key, value);
+        }
+        let opset = a.take_opset();
+
+        group.bench_function(BenchmarkId::new("integrate_opset", pattern), |bench| {
+            bench.iter_batched(
+                || (Store::new("bob"), opset.clone()),
+                |(mut b, opset)| {
+                    b.integrate_opset(opset);
+                    b
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_writes,
+    bench_request_diff,
+    bench_diff,
+    bench_opset
+);
+criterion_main!(benches);
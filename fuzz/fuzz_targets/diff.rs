@@ -0,0 +1,32 @@
+//! Decodes an untrusted diff and integrates it, validated, whole and in chunks
+
+#![no_main]
+
+use cubby::diff::Diff;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(diff) = bincode::deserialize::<Diff<u16, u32>>(data) else {
+        return;
+    };
+    let (mut a, mut b) = cubby_fuzz::stores();
+
+    // a validated diff keeps the store consistent, and a rejected one leaves it unchanged
+    let (mut c, _) = cubby_fuzz::stores();
+    let request = c.request_diff();
+    match c.try_integrate_diff(diff.clone()) {
+        Ok(()) => assert!(c.check_invariants().is_ok()),
+        Err(_) => assert!(c.request_diff() == request),
+    }
+
+    for chunk in diff.clone().split(3) {
+        b.integrate_diff(chunk);
+    }
+    a.integrate_diff(diff);
+
+    // the stores must keep syncing after integrating the diff
+    b.integrate_diff(a.build_diff(b.request_diff()));
+    a.integrate_diff(b.build_diff(a.request_diff()));
+    a.insert(0, 0);
+    a.check_invariants();
+});
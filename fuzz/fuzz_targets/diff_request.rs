@@ -0,0 +1,17 @@
+//! Decodes an untrusted diff request and builds a diff from it
+
+#![no_main]
+
+use cubby::diff::DiffRequest;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(request) = bincode::deserialize::<DiffRequest>(data) else {
+        return;
+    };
+    let (a, mut b) = cubby_fuzz::stores();
+    request.index_size();
+
+    let diff = a.build_diff(request);
+    b.integrate_diff(diff);
+});
@@ -0,0 +1,56 @@
+//! Replaces a KVStore's stored bitmap containers with an untrusted blob and hash,
+//! reopens the store so that no cached bitmap hides them, then reads and commits on top.
+//! Containers only hold 16 bits of HLCs, so `check_invariants` stays bounded.
+
+#![no_main]
+
+use cubby::{kv::KVStore, memory::MemStore};
+use libfuzzer_sys::fuzz_target;
+use rusqlite::Connection;
+
+const PATH: &str = "file:cubby-fuzz?mode=memory&cache=shared";
+
+fuzz_target!(|data: &[u8]| {
+    let Some((hash, state)) = data.split_first_chunk::<8>() else {
+        return;
+    };
+
+    // keeps the shared in-memory database alive while the store is reopened
+    let sqlite = Connection::open(PATH).unwrap();
+    let mut store = KVStore::open_with_local_id(&PATH, b"kv").unwrap();
+    let mut txn = store.begin().unwrap();
+    txn.insert(b"a", b"1").unwrap();
+    txn.insert(b"b", b"2").unwrap();
+    txn.commit().unwrap();
+    drop(store);
+
+    sqlite
+        .execute(
+            "UPDATE bitmap_containers SET state = ?1, hash = ?2",
+            (state, i64::from_be_bytes(*hash)),
+        )
+        .unwrap();
+    let mut store = KVStore::open_with_local_id(&PATH, b"kv").unwrap();
+    let _ = store.check_invariants();
+
+    // a remote holding other entries of the same peer, so that diffs and hashes differ
+    let mut remote = MemStore::<Vec<u8>, Vec<u8>>::new("kv");
+    remote.insert(b"a".to_vec(), b"0".to_vec());
+    if let Ok(encoder) = store.diff_encoder(&remote.request_diff()) {
+        let _ = bincode::serialize(&encoder);
+    }
+    let mut reconciler = remote.reconciler();
+    let mut request = reconciler.request();
+    while let Ok(response) = store.compare_hashes(&request) {
+        let Some(next) = reconciler.refine(response) else {
+            break;
+        };
+        request = next;
+    }
+
+    if let Ok(mut txn) = store.begin() {
+        let _ = txn.insert(b"a", b"3");
+        let _ = txn.delete(b"b");
+        let _ = txn.commit();
+    }
+});
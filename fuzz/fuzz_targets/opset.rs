@@ -0,0 +1,32 @@
+//! Decodes an untrusted opset and integrates it, with and without validation
+
+#![no_main]
+
+use cubby::opset::OpSet;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(opset) = bincode::deserialize::<OpSet<u16, u32>>(data) else {
+        return;
+    };
+    let (mut a, mut b) = cubby_fuzz::stores();
+
+    // a validated opset keeps the store consistent, and a rejected one leaves it unchanged
+    let (mut c, _) = cubby_fuzz::stores();
+    let request = c.request_diff();
+    match c.try_integrate_opset(opset.clone()) {
+        Ok(()) => assert!(c.check_invariants().is_ok()),
+        Err(_) => assert!(c.request_diff() == request),
+    }
+
+    let mut ops = b.take_opset();
+    ops.merge(opset.clone());
+    a.integrate_opset(ops);
+    b.integrate_opset(opset);
+
+    // the stores must keep syncing after integrating the opset
+    b.integrate_diff(a.build_diff(b.request_diff()));
+    a.integrate_diff(b.build_diff(a.request_diff()));
+    b.insert(0, 0);
+    b.check_invariants();
+});
@@ -0,0 +1,31 @@
+//! Shared setup for the fuzz targets.
+//! Messages are decoded with bincode, standing in for an application's wire format.
+//!
+//! Run a target from the repository root with `cargo +nightly fuzz run <target>`.
+
+use cubby::memory::MemStore;
+
+pub type Store = MemStore<u16, u32>;
+
+/// Returns two stores that have synced and then diverged,
+/// so that fuzzed messages meet existing entries, deletes and transactions
+pub fn stores() -> (Store, Store) {
+    let mut a = MemStore::new("alice").with_opset();
+    let mut b = MemStore::new("bob").with_opset();
+    for key in 0..8 {
+        a.insert(key, key as u32);
+    }
+    let mut txn = b.begin();
+    for key in 4..12 {
+        txn.insert(key, key as u32 + 100);
+    }
+    txn.commit();
+
+    b.integrate_diff(a.build_diff(b.request_diff()));
+    a.integrate_diff(b.build_diff(a.request_diff()));
+
+    a.insert(12, 12);
+    a.remove(&0);
+    b.remove(&5);
+    (a, b)
+}
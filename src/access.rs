@@ -0,0 +1,168 @@
+//! Per-peer access control.
+//!
+//! An [`AccessControl`] policy decides which keys each remote peer may read, insert into
+//! or delete from. [`MemStore`] consults its policy in `build_diff_for` and the
+//! `integrate_*_from` methods, which take the ID of the peer on the other end of the
+//! connection. Ops are attributed to that peer rather than to their author, since a
+//! diff's deletes do not record who deleted an entry. Rejected ops are left out and
+//! reported as [`Rejected`].
+//!
+//! [`MemStore`]: crate::memory::MemStore
+
+use std::{
+    collections::HashMap,
+    ops::{Bound, RangeBounds},
+};
+
+/// Policy deciding which keys a remote peer may read, insert into or delete from.
+/// Peers are identified by their public ID.
+pub trait AccessControl<K> {
+    /// Returns `true` if the peer may receive the entry at the key
+    fn can_read(&self, peer: &[u8], key: &K) -> bool;
+
+    /// Returns `true` if the peer may insert or overwrite the key
+    fn can_insert(&self, peer: &[u8], key: &K) -> bool;
+
+    /// Returns `true` if the peer may delete the key
+    fn can_delete(&self, peer: &[u8], key: &K) -> bool;
+}
+
+/// Operations a peer is permitted within a key range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    pub read: bool,
+    pub insert: bool,
+    pub delete: bool,
+}
+
+/// Policy granting each peer permissions on key ranges.
+/// Peers are denied every key outside their granted ranges.
+pub struct KeyRangeAccess<K> {
+    grants: HashMap<Vec<u8>, Vec<Grant<K>>>,
+}
+
+struct Grant<K> {
+    start: Bound<K>,
+    end: Bound<K>,
+    permissions: Permissions,
+}
+
+/// Op rejected by an access control policy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectedOp<K> {
+    /// Author of the inserted or deleted entry
+    pub peer: Vec<u8>,
+    pub hlc: u64,
+    pub key: K,
+}
+
+/// Ops of a diff or opset rejected by an access control policy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejected<K> {
+    pub inserts: Vec<RejectedOp<K>>,
+    pub deletes: Vec<RejectedOp<K>>,
+}
+
+impl Permissions {
+    pub const READ: Permissions = Permissions {
+        read: true,
+        insert: false,
+        delete: false,
+    };
+
+    pub const ALL: Permissions = Permissions {
+        read: true,
+        insert: true,
+        delete: true,
+    };
+}
+
+impl<K: Clone + Ord> KeyRangeAccess<K> {
+    /// Creates a policy that denies every peer
+    pub fn new() -> Self {
+        KeyRangeAccess {
+            grants: HashMap::default(),
+        }
+    }
+
+    /// Grants a peer permissions on a key range.
+    /// Grants are additive, so overlapping ranges combine their permissions.
+    pub fn grant<R: RangeBounds<K>>(
+        mut self,
+        peer: &str,
+        range: R,
+        permissions: Permissions,
+    ) -> Self {
+        self.grants
+            .entry(peer.as_bytes().to_vec())
+            .or_default()
+            .push(Grant {
+                start: range.start_bound().cloned(),
+                end: range.end_bound().cloned(),
+                permissions,
+            });
+        self
+    }
+
+    fn permits(&self, peer: &[u8], key: &K, op: fn(&Permissions) -> bool) -> bool {
+        self.grants.get(peer).is_some_and(|grants| {
+            grants.iter().any(|grant| {
+                op(&grant.permissions) && (grant.start.as_ref(), grant.end.as_ref()).contains(key)
+            })
+        })
+    }
+}
+
+impl<K: Clone + Ord> Default for KeyRangeAccess<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Clone + Ord> AccessControl<K> for KeyRangeAccess<K> {
+    fn can_read(&self, peer: &[u8], key: &K) -> bool {
+        self.permits(peer, key, |permissions| permissions.read)
+    }
+
+    fn can_insert(&self, peer: &[u8], key: &K) -> bool {
+        self.permits(peer, key, |permissions| permissions.insert)
+    }
+
+    fn can_delete(&self, peer: &[u8], key: &K) -> bool {
+        self.permits(peer, key, |permissions| permissions.delete)
+    }
+}
+
+impl<K> Rejected<K> {
+    /// Returns `true` if no ops were rejected
+    pub fn is_empty(&self) -> bool {
+        self.inserts.is_empty() && self.deletes.is_empty()
+    }
+}
+
+impl<K> Default for Rejected<K> {
+    fn default() -> Self {
+        Rejected {
+            inserts: Vec::default(),
+            deletes: Vec::default(),
+        }
+    }
+}
+
+/// Returns the range of byte string keys starting with the prefix
+pub fn prefix(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let start = Bound::Included(prefix.to_vec());
+
+    // the end is the shortest key above every key with the prefix
+    let mut end = prefix.to_vec();
+    while end.last() == Some(&u8::MAX) {
+        end.pop();
+    }
+    match end.last_mut() {
+        Some(last) => {
+            *last += 1;
+            (start, Bound::Excluded(end))
+        }
+        None => (start, Bound::Unbounded),
+    }
+}
@@ -0,0 +1,439 @@
+//! End-to-end encrypted values.
+//!
+//! Values are sealed with XChaCha20-Poly1305 under a shared symmetric key before they
+//! reach the store, so relays sync `Diff`s and `OpSet`s of ciphertext without reading
+//! content. Encryption happens above the store, so each encrypted value is an ordinary
+//! entry to the bitmap-based accounting.
+//!
+//! Each value carries the ID of the key that sealed it and a random per-entry nonce,
+//! and is bound to its key as associated data, so a relay cannot move values between keys.
+//! A [`Keyring`] rotates to new keys while keeping old keys for decryption.
+//!
+//! Keys can optionally be encrypted too. Lookups need a key's ciphertext to be stable, so
+//! keys are sealed deterministically with a synthetic nonce, under a dedicated key that
+//! does not rotate. Equal keys therefore have equal ciphertexts.
+
+use std::collections::BTreeMap;
+
+use chacha20poly1305::{
+    KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, Payload},
+};
+
+#[cfg(feature = "kv")]
+use crate::kv::{self, KVStore, KVStoreTxn};
+#[cfg(feature = "memory")]
+use crate::memory::MemStore;
+
+const KEY_ID_LEN: usize = 4;
+const NONCE_LEN: usize = 24;
+
+/// Symmetric keys for sealing values, by key ID
+pub struct Keyring {
+    keys: BTreeMap<u32, XChaCha20Poly1305>,
+    current: u32,
+    key_cipher: Option<KeyCipher>,
+}
+
+// Deterministic cipher for keys, with separate subkeys for nonces and encryption
+struct KeyCipher {
+    nonce_key: [u8; 32],
+    cipher: XChaCha20Poly1305,
+}
+
+/// Error returned when a value or key cannot be decrypted
+#[derive(Debug, thiserror::Error)]
+pub enum CryptError {
+    #[error("unknown key ID {0}")]
+    UnknownKey(u32),
+    #[error("malformed ciphertext")]
+    Malformed,
+    #[error("cannot decrypt ciphertext")]
+    Decrypt,
+}
+
+impl Keyring {
+    /// Creates a keyring that seals values with the key
+    pub fn new(id: u32, key: &[u8; 32]) -> Self {
+        let mut keys = BTreeMap::new();
+        keys.insert(id, XChaCha20Poly1305::new(key.into()));
+        Keyring {
+            keys,
+            current: id,
+            key_cipher: None,
+        }
+    }
+
+    /// Enables deterministic key encryption with a dedicated key
+    pub fn with_key_encryption(mut self, key: &[u8; 32]) -> Self {
+        let nonce_key = blake3::derive_key("cubby key encryption nonce", key);
+        let cipher_key = blake3::derive_key("cubby key encryption cipher", key);
+        self.key_cipher = Some(KeyCipher {
+            nonce_key,
+            cipher: XChaCha20Poly1305::new(&cipher_key.into()),
+        });
+        self
+    }
+
+    /// Adds a key that is only used to open values sealed before a rotation
+    pub fn add(&mut self, id: u32, key: &[u8; 32]) {
+        self.keys.insert(id, XChaCha20Poly1305::new(key.into()));
+    }
+
+    /// Adds a key and seals all new values with it
+    pub fn rotate(&mut self, id: u32, key: &[u8; 32]) {
+        self.add(id, key);
+        self.current = id;
+    }
+
+    /// Removes a key, returning `true` if it was present.
+    /// The current key cannot be removed.
+    pub fn remove(&mut self, id: u32) -> bool {
+        id != self.current && self.keys.remove(&id).is_some()
+    }
+
+    /// Returns the ID of the key that seals new values
+    pub fn current(&self) -> u32 {
+        self.current
+    }
+
+    /// Seals a value stored at `key` under the current key, with a random nonce
+    pub fn seal(&self, key: &[u8], value: &[u8]) -> Vec<u8> {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let ciphertext = self.keys[&self.current]
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: value,
+                    aad: key,
+                },
+            )
+            .expect("value is within the AEAD's size limit");
+
+        let mut sealed = Vec::with_capacity(KEY_ID_LEN + NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(&self.current.to_be_bytes());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Opens a value stored at `key`
+    pub fn open(&self, key: &[u8], sealed: &[u8]) -> Result<Vec<u8>, CryptError> {
+        let id = Self::key_id(sealed)?;
+        let cipher = self.keys.get(&id).ok_or(CryptError::UnknownKey(id))?;
+        let (nonce, ciphertext) = sealed[KEY_ID_LEN..].split_at(NONCE_LEN);
+        cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: key,
+                },
+            )
+            .map_err(|_| CryptError::Decrypt)
+    }
+
+    /// Returns the ID of the key that sealed a value
+    pub fn key_id(sealed: &[u8]) -> Result<u32, CryptError> {
+        if sealed.len() < KEY_ID_LEN + NONCE_LEN {
+            return Err(CryptError::Malformed);
+        }
+        let id = sealed[..KEY_ID_LEN].try_into().expect("length is checked");
+        Ok(u32::from_be_bytes(id))
+    }
+
+    /// Encrypts a key deterministically, if key encryption is enabled
+    pub fn seal_key(&self, key: &[u8]) -> Vec<u8> {
+        let Some(key_cipher) = &self.key_cipher else {
+            return key.to_vec();
+        };
+        let hash = blake3::keyed_hash(&key_cipher.nonce_key, key);
+        let nonce = &hash.as_bytes()[..NONCE_LEN];
+        let ciphertext = key_cipher
+            .cipher
+            .encrypt(XNonce::from_slice(nonce), key)
+            .expect("key is within the AEAD's size limit");
+
+        let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Decrypts a key, if key encryption is enabled
+    pub fn open_key(&self, sealed: &[u8]) -> Result<Vec<u8>, CryptError> {
+        let Some(key_cipher) = &self.key_cipher else {
+            return Ok(sealed.to_vec());
+        };
+        if sealed.len() < NONCE_LEN {
+            return Err(CryptError::Malformed);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let key = key_cipher
+            .cipher
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| CryptError::Decrypt)?;
+
+        // the synthetic nonce must match the key
+        let hash = blake3::keyed_hash(&key_cipher.nonce_key, &key);
+        if hash.as_bytes()[..NONCE_LEN] != *nonce {
+            return Err(CryptError::Decrypt);
+        }
+        Ok(key)
+    }
+}
+
+/// MemStore of encrypted entries.
+/// Sync the inner store with [`EncryptedMemStore::store_mut`].
+#[cfg(feature = "memory")]
+pub struct EncryptedMemStore {
+    store: MemStore<Vec<u8>, Vec<u8>>,
+    keyring: Keyring,
+}
+
+#[cfg(feature = "memory")]
+impl EncryptedMemStore {
+    /// Wraps a store of encrypted entries
+    pub fn new(store: MemStore<Vec<u8>, Vec<u8>>, keyring: Keyring) -> Self {
+        EncryptedMemStore { store, keyring }
+    }
+
+    /// Returns the inner store of encrypted entries
+    pub fn store(&self) -> &MemStore<Vec<u8>, Vec<u8>> {
+        &self.store
+    }
+
+    /// Returns the inner store of encrypted entries, e.g. to sync it
+    pub fn store_mut(&mut self) -> &mut MemStore<Vec<u8>, Vec<u8>> {
+        &mut self.store
+    }
+
+    /// Returns the keyring, e.g. to rotate keys
+    pub fn keyring_mut(&mut self) -> &mut Keyring {
+        &mut self.keyring
+    }
+
+    /// Encrypts and inserts a key-value pair
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) {
+        let key = self.keyring.seal_key(key);
+        let value = self.keyring.seal(&key, value);
+        self.store.insert(key, value);
+    }
+
+    /// Removes a key
+    pub fn remove(&mut self, key: &[u8]) {
+        self.store.remove(&self.keyring.seal_key(key));
+    }
+
+    /// Returns the decrypted value at the key
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, CryptError> {
+        let key = self.keyring.seal_key(key);
+        self.store
+            .get(&key)
+            .map(|value| self.keyring.open(&key, value))
+            .transpose()
+    }
+
+    /// Returns the decrypted entries, ordered by decrypted key
+    pub fn entries(&self) -> Result<BTreeMap<Vec<u8>, Vec<u8>>, CryptError> {
+        self.store
+            .entries()
+            .iter()
+            .map(|(key, value)| Ok((self.keyring.open_key(key)?, self.keyring.open(key, value)?)))
+            .collect()
+    }
+
+    /// Reseals every value sealed under an old key with the current key, in one
+    /// transaction, returning the number of resealed values.
+    /// Resealed values are ordinary local writes, so they sync like any other insert.
+    pub fn reseal(&mut self) -> Result<usize, CryptError> {
+        let mut resealed = Vec::new();
+        for (key, value) in self.store.entries().iter() {
+            if Keyring::key_id(value)? != self.keyring.current() {
+                let value = self.keyring.open(key, value)?;
+                resealed.push((key.clone(), self.keyring.seal(key, &value)));
+            }
+        }
+
+        let count = resealed.len();
+        let mut txn = self.store.begin();
+        for (key, value) in resealed {
+            txn.insert(key, value);
+        }
+        txn.commit();
+        Ok(count)
+    }
+}
+
+/// KVStore of encrypted entries
+#[cfg(feature = "kv")]
+pub struct EncryptedKVStore {
+    store: KVStore,
+    keyring: Keyring,
+}
+
+/// EncryptedKVStore transactional context
+#[cfg(feature = "kv")]
+pub struct EncryptedKVStoreTxn<'a> {
+    txn: KVStoreTxn<'a>,
+    keyring: &'a Keyring,
+}
+
+#[cfg(feature = "kv")]
+impl EncryptedKVStore {
+    /// Wraps a store of encrypted entries
+    pub fn new(store: KVStore, keyring: Keyring) -> Self {
+        EncryptedKVStore { store, keyring }
+    }
+
+    /// Returns the inner store of encrypted entries
+    pub fn store_mut(&mut self) -> &mut KVStore {
+        &mut self.store
+    }
+
+    /// Returns the keyring, e.g. to rotate keys
+    pub fn keyring_mut(&mut self) -> &mut Keyring {
+        &mut self.keyring
+    }
+
+    /// Begins a transaction
+    pub fn begin(&mut self) -> Result<EncryptedKVStoreTxn<'_>, kv::Error> {
+        Ok(EncryptedKVStoreTxn {
+            txn: self.store.begin()?,
+            keyring: &self.keyring,
+        })
+    }
+}
+
+#[cfg(feature = "kv")]
+impl EncryptedKVStoreTxn<'_> {
+    /// Get the decrypted value for a key
+    pub fn get(&self, key: &[u8]) -> Result<Vec<u8>, kv::Error> {
+        let key = self.keyring.seal_key(key);
+        let value = self.txn.get(&key)?;
+        Ok(self.keyring.open(&key, &value)?)
+    }
+
+    /// Encrypt and insert a key value pair
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), kv::Error> {
+        let key = self.keyring.seal_key(key);
+        let value = self.keyring.seal(&key, value);
+        self.txn.insert(&key, &value)
+    }
+
+    /// Delete a key
+    pub fn delete(&mut self, key: &[u8]) -> Result<(), kv::Error> {
+        self.txn.delete(&self.keyring.seal_key(key))
+    }
+
+    /// Reseal every value sealed under an old key with the current key,
+    /// returning the number of resealed values
+    pub fn reseal(&mut self) -> Result<usize, kv::Error> {
+        let mut count = 0;
+        for key in self.txn.keys()? {
+            let value = self.txn.get(&key)?;
+            if Keyring::key_id(&value)? != self.keyring.current() {
+                let value = self.keyring.open(&key, &value)?;
+                self.txn.insert(&key, &self.keyring.seal(&key, &value))?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Commit the transaction
+    pub fn commit(self) -> Result<(), kv::Error> {
+        self.txn.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_A: [u8; 32] = [1; 32];
+    const KEY_B: [u8; 32] = [2; 32];
+
+    #[test]
+    fn test_relay_cannot_read() {
+        let mut a = EncryptedMemStore::new(MemStore::new("alice"), Keyring::new(0, &KEY_A));
+        let mut b = EncryptedMemStore::new(MemStore::new("bob"), Keyring::new(0, &KEY_A));
+        let mut hub = MemStore::new("hub");
+        a.insert(b"key", b"secret");
+
+        hub.integrate_diff(a.store().build_diff(hub.request_diff()));
+        let sealed = hub.entries().get(&b"key".to_vec()).unwrap().clone();
+        assert!(!sealed.windows(6).any(|window| window == b"secret"));
+
+        let request = b.store().request_diff();
+        b.store_mut().integrate_diff(hub.build_diff(request));
+        assert_eq!(b.get(b"key").unwrap().unwrap(), b"secret");
+        assert!(b.store().check_invariants().is_ok());
+
+        // values are bound to their keys
+        let keyring = Keyring::new(0, &KEY_A);
+        assert_eq!(keyring.open(b"key", &sealed).unwrap(), b"secret");
+        assert!(matches!(
+            keyring.open(b"other", &sealed),
+            Err(CryptError::Decrypt)
+        ));
+        assert!(matches!(
+            keyring.open(b"key", &sealed[..10]),
+            Err(CryptError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn test_rotation() {
+        let mut a = EncryptedMemStore::new(
+            MemStore::new("alice"),
+            Keyring::new(0, &KEY_A).with_key_encryption(&[3; 32]),
+        );
+        a.insert(b"one", b"1");
+        a.keyring_mut().rotate(1, &KEY_B);
+        a.insert(b"two", b"2");
+        assert!(!a.keyring_mut().remove(1));
+        assert!(a.store().get(&b"one".to_vec()).is_none());
+
+        // old values stay readable until resealed
+        assert_eq!(a.get(b"one").unwrap().unwrap(), b"1");
+        assert_eq!(a.reseal().unwrap(), 1);
+        assert_eq!(a.reseal().unwrap(), 0);
+        assert!(a.keyring_mut().remove(0));
+        assert_eq!(
+            a.entries().unwrap(),
+            BTreeMap::from([
+                (b"one".to_vec(), b"1".to_vec()),
+                (b"two".to_vec(), b"2".to_vec())
+            ])
+        );
+
+        let old = Keyring::new(0, &KEY_A).with_key_encryption(&[3; 32]);
+        let key = old.seal_key(b"one");
+        let value = a.store().get(&key).unwrap();
+        assert!(matches!(
+            old.open(&key, value),
+            Err(CryptError::UnknownKey(1))
+        ));
+    }
+
+    #[cfg(feature = "kv")]
+    #[test]
+    fn test_kv_store() {
+        let keyring = Keyring::new(0, &KEY_A).with_key_encryption(&[3; 32]);
+        let mut store = EncryptedKVStore::new(KVStore::open(&":memory:").unwrap(), keyring);
+        let mut txn = store.begin().unwrap();
+        txn.insert(b"a", b"1").unwrap();
+        txn.insert(b"b", b"2").unwrap();
+        txn.delete(b"b").unwrap();
+        txn.commit().unwrap();
+
+        store.keyring_mut().rotate(1, &KEY_B);
+        let mut txn = store.begin().unwrap();
+        assert_eq!(txn.reseal().unwrap(), 1);
+        assert_eq!(txn.get(b"a").unwrap(), b"1");
+        assert!(txn.get(b"b").is_err());
+        txn.commit().unwrap();
+        assert!(store.store_mut().check_invariants().unwrap().is_ok());
+    }
+}
@@ -1,39 +1,146 @@
-use std::collections::HashMap;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, hash_map::Entry},
+};
 
+use bytes::Bytes;
 use roaring::RoaringTreemap;
-use serde::{Deserialize, Serialize};
+use serde::{
+    Deserialize, Deserializer, Serialize, Serializer,
+    de::{self, SeqAccess, Visitor},
+};
 
-use crate::{hlc::Hlc, peer_id::PeerId};
+use crate::{filter::KeyFilter, hlc::Hlc, peer_id::PeerId};
 
 /// State diff request
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DiffRequest(pub(crate) HashMap<PeerId, DiffRequestPeerState>);
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub(crate) struct DiffRequestPeerState {
-    #[serde(skip_serializing_if = "RoaringTreemap::is_empty")]
-    pub index: RoaringTreemap,
+    #[serde(default, skip_serializing_if = "RequestIndex::is_empty")]
+    pub index: RequestIndex,
     pub bookmark: Hlc,
 }
 
+// Peer index carried by a request, either as a bitmap or as the bitmap's serialized bytes.
+// Both serialize identically, and deserialization always yields a bitmap.
+#[derive(Debug, Clone)]
+pub(crate) enum RequestIndex {
+    Bitmap(RoaringTreemap),
+    // Never empty, so that empty indexes are skipped when serializing.
+    // Only MemStore caches serialized indexes.
+    #[cfg(feature = "memory")]
+    Serialized(Bytes),
+}
+
+/// State diff request for a subset of the peers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SubsetDiffRequest(pub(crate) DiffRequest);
+
+/// State diff request for the keys matching a filter
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FilteredDiffRequest<K> {
+    pub(crate) filter: KeyFilter<K>,
+    pub(crate) request: DiffRequest,
+}
+
 /// State diff
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Diff<K, V>(pub(crate) HashMap<PeerId, DiffPeerState<K, V>>);
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub(crate) struct DiffPeerState<K, V> {
-    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
     pub inserts: Vec<Insert<K, V>>,
-    #[serde(skip_serializing_if = "RoaringTreemap::is_empty")]
+    #[serde(default, skip_serializing_if = "RoaringTreemap::is_empty")]
     pub deletes: RoaringTreemap,
     pub bookmark: Hlc,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub txns: Vec<TxnRange>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub(crate) struct Insert<K, V> {
     pub key: K,
     pub value: V,
     pub hlc: Hlc,
+    // Kept without the `signing` feature, so that unsigned stores relay signatures.
+    // Unsigned inserts skip the field, so it costs nothing on the wire.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<Bytes>,
+}
+
+/// State diff borrowing its entries from a store, serialized like a [`Diff`]
+#[derive(Serialize)]
+#[serde(rename = "Diff")]
+pub struct DiffRef<'a, K, V>(pub(crate) HashMap<&'a PeerId, DiffPeerStateRef<'a, K, V>>);
+
+#[derive(Serialize)]
+#[serde(rename = "DiffPeerState")]
+pub(crate) struct DiffPeerStateRef<'a, K, V> {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub inserts: Vec<InsertRef<'a, K, V>>,
+    #[serde(skip_serializing_if = "RoaringTreemap::is_empty")]
+    pub deletes: RoaringTreemap,
+    pub bookmark: Hlc,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub txns: Vec<TxnRange>,
+}
+
+#[derive(Serialize)]
+#[serde(rename = "Insert")]
+pub(crate) struct InsertRef<'a, K, V> {
+    pub key: &'a K,
+    pub value: &'a V,
+    pub hlc: Hlc,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<&'a Bytes>,
+}
+
+// Byte-oriented diff as decoded from a receive buffer, laid out like `Diff<Bytes, Bytes>`
+#[derive(Deserialize)]
+#[serde(rename = "Diff")]
+struct BufferDiff<'a>(#[serde(borrow)] HashMap<PeerId, BufferPeerState<'a>>);
+
+#[derive(Deserialize)]
+#[serde(rename = "DiffPeerState")]
+struct BufferPeerState<'a> {
+    #[serde(borrow, default)]
+    inserts: Vec<BufferInsert<'a>>,
+    #[serde(default)]
+    deletes: RoaringTreemap,
+    bookmark: Hlc,
+    #[serde(default)]
+    txns: Vec<TxnRange>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "Insert")]
+struct BufferInsert<'a> {
+    #[serde(borrow)]
+    key: BufferSlice<'a>,
+    #[serde(borrow)]
+    value: BufferSlice<'a>,
+    hlc: Hlc,
+    #[serde(borrow, default)]
+    signature: Option<BufferSlice<'a>>,
+}
+
+// Byte string borrowed from the receive buffer when the format allows it
+enum BufferSlice<'a> {
+    Borrowed(&'a [u8]),
+    Owned(Vec<u8>),
+}
+
+/// Contiguous, inclusive HLC range allocated to a single transaction
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct TxnRange {
+    pub start: Hlc,
+    pub end: Hlc,
+    // set if the transaction also deleted entries, so its inserts travel with the deletes
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deletes: bool,
 }
 
 impl DiffRequest {
@@ -43,9 +150,399 @@ impl DiffRequest {
     }
 }
 
+impl SubsetDiffRequest {
+    /// Returns the underlying request, e.g. to check it against limits
+    pub fn request(&self) -> &DiffRequest {
+        &self.0
+    }
+}
+
+impl<K> FilteredDiffRequest<K> {
+    /// Returns the key filter
+    pub fn filter(&self) -> &KeyFilter<K> {
+        &self.filter
+    }
+
+    /// Returns the underlying request, e.g. to check it against limits
+    pub fn request(&self) -> &DiffRequest {
+        &self.request
+    }
+}
+
+impl Diff<Bytes, Bytes> {
+    /// Decodes a diff from a deserializer reading the buffer.
+    /// Keys, values and signatures the format can borrow are sliced out of the buffer
+    /// rather than copied, so the decoded entries share its allocation.
+    pub fn decode<'de, D: Deserializer<'de>>(
+        buffer: &'de Bytes,
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        let BufferDiff(peers) = BufferDiff::deserialize(deserializer)?;
+        Ok(Diff(
+            peers
+                .into_iter()
+                .map(|(peer_id, state)| {
+                    let inserts = state
+                        .inserts
+                        .into_iter()
+                        .map(|insert| Insert {
+                            key: insert.key.into_bytes(buffer),
+                            value: insert.value.into_bytes(buffer),
+                            hlc: insert.hlc,
+                            signature: insert.signature.map(|slice| slice.into_bytes(buffer)),
+                        })
+                        .collect();
+                    let state = DiffPeerState {
+                        inserts,
+                        deletes: state.deletes,
+                        bookmark: state.bookmark,
+                        txns: state.txns,
+                    };
+                    (peer_id, state)
+                })
+                .collect(),
+        ))
+    }
+}
+
+impl<K, V> Diff<K, V> {
+    /// Returns `true` if the diff carries no inserts or deletes
+    pub fn is_empty(&self) -> bool {
+        self.0
+            .values()
+            .all(|state| state.inserts.is_empty() && state.deletes.is_empty())
+    }
+
+    /// Splits the diff into chunks of at most `max_inserts` inserts each.
+    /// A transaction's inserts are never split across chunks, so a transaction
+    /// larger than `max_inserts` gets a chunk of its own.
+    /// Deletes and bookmarks are carried by the final chunk, along with the inserts of
+    /// transactions that also deleted entries, so the final chunk may exceed `max_inserts`.
+    /// Chunks must be integrated in order.
+    pub fn split(self, max_inserts: usize) -> Vec<Diff<K, V>> {
+        let mut chunks = Vec::new();
+        let mut chunk: HashMap<PeerId, DiffPeerState<K, V>> = HashMap::default();
+        let mut chunk_len = 0;
+        let mut last = HashMap::with_capacity(self.0.len());
+
+        for (peer_id, state) in self.0 {
+            let mut txns = state.txns.iter().peekable();
+            let mut inserts = state.inserts.into_iter().peekable();
+            let mut deferred = Vec::new();
+            let mut deferred_txns = Vec::new();
+
+            while let Some(insert) = inserts.next() {
+                // group the insert with the rest of its transaction
+                while txns.next_if(|txn| txn.end < insert.hlc).is_some() {}
+                let txn = txns.peek().filter(|txn| txn.start <= insert.hlc).copied();
+                let mut group = vec![insert];
+                if let Some(txn) = txn {
+                    while let Some(insert) = inserts.next_if(|insert| insert.hlc <= txn.end) {
+                        group.push(insert);
+                    }
+                    if txn.deletes {
+                        deferred.append(&mut group);
+                        deferred_txns.push(*txn);
+                        continue;
+                    }
+                }
+
+                if chunk_len > 0 && chunk_len + group.len() > max_inserts {
+                    chunks.push(Diff(std::mem::take(&mut chunk)));
+                    chunk_len = 0;
+                }
+
+                chunk_len += group.len();
+                let chunk_state = chunk
+                    .entry(peer_id.clone())
+                    .or_insert_with(|| DiffPeerState {
+                        inserts: Vec::default(),
+                        deletes: RoaringTreemap::new(),
+                        bookmark: Hlc::default(),
+                        txns: Vec::default(),
+                    });
+                chunk_state.inserts.append(&mut group);
+                chunk_state.txns.extend(txn.copied());
+            }
+
+            last.insert(
+                peer_id,
+                DiffPeerState {
+                    inserts: deferred,
+                    deletes: state.deletes,
+                    bookmark: state.bookmark,
+                    txns: deferred_txns,
+                },
+            );
+        }
+
+        // the final chunk carries all deletes and bookmarks,
+        // and the transactions that must arrive with them
+        for (peer_id, mut state) in last {
+            match chunk.entry(peer_id) {
+                Entry::Occupied(mut entry) => {
+                    let entry = entry.get_mut();
+                    entry.inserts.append(&mut state.inserts);
+                    entry.inserts.sort_unstable_by_key(|insert| insert.hlc);
+                    entry.txns.append(&mut state.txns);
+                    entry.txns.sort_unstable_by_key(|txn| txn.start);
+                    entry.deletes = state.deletes;
+                    entry.bookmark = state.bookmark;
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(state);
+                }
+            }
+        }
+        chunks.push(Diff(chunk));
+        chunks
+    }
+}
+
+impl<K, V> DiffRef<'_, K, V> {
+    /// Returns `true` if the diff carries no inserts or deletes
+    pub fn is_empty(&self) -> bool {
+        self.0
+            .values()
+            .all(|state| state.inserts.is_empty() && state.deletes.is_empty())
+    }
+}
+
+impl<K: Clone, V: Clone> DiffRef<'_, K, V> {
+    /// Clones the borrowed entries into an owned diff
+    pub fn into_owned(self) -> Diff<K, V> {
+        Diff(
+            self.0
+                .into_iter()
+                .map(|(peer_id, state)| (peer_id.clone(), state.into_owned()))
+                .collect(),
+        )
+    }
+}
+
+impl<K: Clone, V: Clone> DiffPeerStateRef<'_, K, V> {
+    pub(crate) fn into_owned(self) -> DiffPeerState<K, V> {
+        DiffPeerState {
+            inserts: self
+                .inserts
+                .into_iter()
+                .map(InsertRef::into_owned)
+                .collect(),
+            deletes: self.deletes,
+            bookmark: self.bookmark,
+            txns: self.txns,
+        }
+    }
+}
+
+impl<K: Clone, V: Clone> InsertRef<'_, K, V> {
+    pub(crate) fn into_owned(self) -> Insert<K, V> {
+        Insert {
+            key: self.key.clone(),
+            value: self.value.clone(),
+            hlc: self.hlc,
+            signature: self.signature.cloned(),
+        }
+    }
+}
+
 impl DiffRequestPeerState {
     /// Returns the index size, in bytes
     pub fn index_size(&self) -> usize {
-        self.index.serialized_size()
+        match &self.index {
+            RequestIndex::Bitmap(index) => index.serialized_size(),
+            #[cfg(feature = "memory")]
+            RequestIndex::Serialized(bytes) => bytes.len(),
+        }
+    }
+}
+
+impl RequestIndex {
+    /// Returns the index as a bitmap, deserializing it if needed
+    pub fn bitmap(&self) -> Cow<'_, RoaringTreemap> {
+        match self {
+            RequestIndex::Bitmap(index) => Cow::Borrowed(index),
+            #[cfg(feature = "memory")]
+            RequestIndex::Serialized(bytes) => Cow::Owned(
+                RoaringTreemap::deserialize_unchecked_from(&bytes[..]).expect("serialized locally"),
+            ),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            RequestIndex::Bitmap(index) => index.is_empty(),
+            #[cfg(feature = "memory")]
+            RequestIndex::Serialized(_) => false,
+        }
+    }
+}
+
+impl Default for RequestIndex {
+    fn default() -> Self {
+        RequestIndex::Bitmap(RoaringTreemap::new())
+    }
+}
+
+impl From<RoaringTreemap> for RequestIndex {
+    fn from(index: RoaringTreemap) -> Self {
+        RequestIndex::Bitmap(index)
+    }
+}
+
+impl PartialEq for RequestIndex {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            #[cfg(feature = "memory")]
+            (RequestIndex::Serialized(a), RequestIndex::Serialized(b)) => a == b,
+            _ => self.bitmap() == other.bitmap(),
+        }
+    }
+}
+
+impl Serialize for RequestIndex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            RequestIndex::Bitmap(index) => index.serialize(serializer),
+            #[cfg(feature = "memory")]
+            RequestIndex::Serialized(bytes) => serializer.serialize_bytes(bytes),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for RequestIndex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        RoaringTreemap::deserialize(deserializer).map(RequestIndex::Bitmap)
+    }
+}
+
+impl BufferSlice<'_> {
+    // Slices borrowed bytes out of the buffer, copying them if they lie outside of it
+    fn into_bytes(self, buffer: &Bytes) -> Bytes {
+        match self {
+            BufferSlice::Borrowed(slice) if within(buffer, slice) => buffer.slice_ref(slice),
+            BufferSlice::Borrowed(slice) => Bytes::copy_from_slice(slice),
+            BufferSlice::Owned(bytes) => bytes.into(),
+        }
+    }
+}
+
+fn within(buffer: &[u8], slice: &[u8]) -> bool {
+    let buffer = buffer.as_ptr_range();
+    let slice = slice.as_ptr_range();
+    buffer.start <= slice.start && slice.end <= buffer.end
+}
+
+impl<'de: 'a, 'a> Deserialize<'de> for BufferSlice<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_bytes(BufferSliceVisitor)
+    }
+}
+
+struct BufferSliceVisitor;
+
+impl<'de> Visitor<'de> for BufferSliceVisitor {
+    type Value = BufferSlice<'de>;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        formatter.write_str("a byte string")
+    }
+
+    fn visit_borrowed_bytes<E: de::Error>(self, v: &'de [u8]) -> Result<Self::Value, E> {
+        Ok(BufferSlice::Borrowed(v))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        Ok(BufferSlice::Owned(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(BufferSlice::Owned(v))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or_default().min(4096));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(BufferSlice::Owned(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::de::value::{BorrowedBytesDeserializer, BytesDeserializer, Error};
+
+    use super::*;
+
+    #[test]
+    fn test_buffer_slices() {
+        let buffer = Bytes::from_static(b"key value");
+
+        // borrowed bytes are sliced out of the buffer
+        let slice = BufferSlice::deserialize(BorrowedBytesDeserializer::<Error>::new(&buffer[4..]))
+            .unwrap()
+            .into_bytes(&buffer);
+        assert_eq!(slice, "value");
+        assert_eq!(slice.as_ptr(), buffer[4..].as_ptr());
+
+        // bytes borrowed from elsewhere, or not borrowed, are copied
+        let other = b"value".to_vec();
+        let slice = BufferSlice::deserialize(BorrowedBytesDeserializer::<Error>::new(&other))
+            .unwrap()
+            .into_bytes(&buffer);
+        assert_eq!(slice, "value");
+        assert_ne!(slice.as_ptr(), other.as_ptr());
+        let slice = BufferSlice::deserialize(BytesDeserializer::<Error>::new(&buffer[..3]))
+            .unwrap()
+            .into_bytes(&buffer);
+        assert_eq!(slice, "key");
+        assert_ne!(slice.as_ptr(), buffer.as_ptr());
+    }
+
+    // Diff setting every field, so that decoding can't drop one unnoticed
+    fn full_diff() -> Diff<Bytes, Bytes> {
+        let hlc = Hlc::new(1 << 16, 0);
+        let state = DiffPeerState {
+            inserts: vec![Insert {
+                key: Bytes::from_static(b"key"),
+                value: Bytes::from_static(b"value"),
+                hlc,
+                signature: Some(Bytes::from_static(b"signature")),
+            }],
+            deletes: RoaringTreemap::from_iter([1, 2]),
+            bookmark: hlc,
+            txns: vec![TxnRange {
+                start: hlc,
+                end: hlc,
+                deletes: true,
+            }],
+        };
+        Diff(HashMap::from_iter([(PeerId::from_str("alice"), state)]))
+    }
+
+    #[test]
+    fn test_decode_borrows_buffer() {
+        let buffer = Bytes::from(rmp_serde::to_vec_named(&full_diff()).unwrap());
+        let mut deserializer = rmp_serde::Deserializer::from_read_ref(&buffer[..]);
+        let diff = Diff::decode(&buffer, &mut deserializer).unwrap();
+
+        let insert = &diff.0[&PeerId::from_str("alice")].inserts[0];
+        assert_eq!(insert.key, "key");
+        assert_eq!(insert.value, "value");
+        let signature = insert.signature.as_ref().unwrap();
+        for slice in [&insert.key, &insert.value, signature] {
+            assert!(within(&buffer, slice), "{slice:?} was copied");
+        }
+    }
+
+    #[test]
+    fn test_decode_round_trip() {
+        // the buffer layout decodes everything a `Diff` serializes, in the same layout
+        let encoded = rmp_serde::to_vec_named(&full_diff()).unwrap();
+        let buffer = Bytes::from(encoded.clone());
+        let mut deserializer = rmp_serde::Deserializer::from_read_ref(&buffer[..]);
+        let diff = Diff::decode(&buffer, &mut deserializer).unwrap();
+        assert_eq!(rmp_serde::to_vec_named(&diff).unwrap(), encoded);
     }
 }
@@ -0,0 +1,66 @@
+//! Key filters for partial replication.
+//!
+//! A partial replica syncs only the keys matching a [`KeyFilter`]. Both directions
+//! start from [`MemStore::request_filtered_diff`], whose index only covers keys in the
+//! filter, so deletes are only ever inferred for keys the replica subscribes to.
+//!
+//! A replica pulls with [`MemStore::integrate_filtered_diff`], which records the remote
+//! bookmarks as the filter's sync state rather than as full bookmarks. The full
+//! bookmarks stay accurate, so the replica can still serve full diffs, and a replica
+//! that changes its filter starts the new filter's sync state from scratch.
+//!
+//! [`MemStore::request_filtered_diff`]: crate::memory::MemStore::request_filtered_diff
+//! [`MemStore::integrate_filtered_diff`]: crate::memory::MemStore::integrate_filtered_diff
+
+use std::ops::{Bound, RangeBounds};
+
+use serde::{Deserialize, Serialize};
+
+use crate::access;
+
+/// Set of key ranges
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyFilter<K> {
+    ranges: Vec<(Bound<K>, Bound<K>)>,
+}
+
+impl<K: Clone + Ord> KeyFilter<K> {
+    /// Creates a filter that matches no keys
+    pub fn new() -> Self {
+        KeyFilter { ranges: Vec::new() }
+    }
+
+    /// Adds a key range to the filter
+    pub fn range<R: RangeBounds<K>>(mut self, range: R) -> Self {
+        self.ranges
+            .push((range.start_bound().cloned(), range.end_bound().cloned()));
+        self
+    }
+
+    /// Returns `true` if the key is in any of the filter's ranges
+    pub fn contains(&self, key: &K) -> bool {
+        self.ranges
+            .iter()
+            .any(|(start, end)| (start.as_ref(), end.as_ref()).contains(key))
+    }
+}
+
+impl<K> KeyFilter<K> {
+    // Returns the number of ranges, which bounds the cost of `contains`
+    pub(crate) fn len(&self) -> usize {
+        self.ranges.len()
+    }
+}
+
+impl KeyFilter<Vec<u8>> {
+    /// Adds the keys starting with the prefix to the filter
+    pub fn prefix(self, prefix: &[u8]) -> Self {
+        self.range(access::prefix(prefix))
+    }
+}
+
+impl<K: Clone + Ord> Default for KeyFilter<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
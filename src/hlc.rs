@@ -1,4 +1,4 @@
-#[cfg(test)]
+#[cfg(any(test, feature = "sim"))]
 use std::cell::RefCell;
 use std::cmp::max;
 use std::fmt;
@@ -6,10 +6,10 @@ use std::time::{SystemTime, UNIX_EPOCH};
 
 use serde::{Deserialize, Serialize};
 
-// set a mock thread-local static physical time during testing.
-#[cfg(test)]
+// set a mock thread-local static physical time during testing and simulation.
+#[cfg(any(test, feature = "sim"))]
 std::thread_local! {
-    static MOCK_PT: RefCell<Option<u64>> = RefCell::new(None);
+    static MOCK_PT: RefCell<Option<u64>> = const { RefCell::new(None) };
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Hash, Serialize, Deserialize)]
@@ -46,20 +46,26 @@ impl Hlc {
 
     /// Creates a new HLC from an existing, local HLC.
     /// If physical time (pt) has changed, l is set to pt and c is set to 0.
-    /// If pt has not changed, c is incremented.
+    /// If pt has not changed, c is incremented, saturating at the maximum HLC.
     #[inline]
     pub fn next(self) -> Self {
-        #[cfg(test)]
-        if let Some(pt) = MOCK_PT.with(|f| (*f.borrow()).clone()) {
-            return self.next_inner(pt);
+        self.next_inner(Self::pt())
+    }
+
+    /// Returns the current physical time, in microseconds truncated to an HLC's l
+    #[inline]
+    pub fn pt() -> u64 {
+        #[cfg(any(test, feature = "sim"))]
+        if let Some(pt) = MOCK_PT.with(|f| *f.borrow()) {
+            return pt;
         }
-        self.next_inner(Self::makept())
+        Self::makept()
     }
 
-    /// Increments the HLC by one
+    /// Increments the HLC by one, saturating at the maximum HLC
     #[inline]
     pub fn inc(self) -> Self {
-        Self(self.0 + 1)
+        Self(self.0.saturating_add(1))
     }
 
     #[inline]
@@ -67,26 +73,30 @@ impl Hlc {
         let l = max(self.l(), pt);
 
         if l == self.l() {
-            Hlc(self.0 + 1)
+            self.inc()
         } else {
             Hlc::new(l, 0)
         }
     }
 
+    // Earlier versions added subsecond nanoseconds to microseconds, running up to
+    // 1000 seconds ahead. HLCs they persisted stay valid, since `next` never moves l
+    // backwards; until real time catches up, their writes win over concurrent ones,
+    // and `try_integrate_*` rejects those beyond the max drift as future HLCs.
     fn makept() -> u64 {
         let duration = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("time cannot go backwards");
-        let nsec = duration.as_secs() * 1_000_000 + duration.subsec_nanos() as u64;
-        nsec & 0xFFFF_FFFF_FFFF_0000
+        let usec = duration.as_secs() * 1_000_000 + duration.subsec_micros() as u64;
+        usec & 0xFFFF_FFFF_FFFF_0000
     }
 
-    #[cfg(test)]
+    #[cfg(any(test, feature = "sim"))]
     pub fn set_mock_pt(pt: u64) {
         MOCK_PT.with(|f| *f.borrow_mut() = Some(pt))
     }
 
-    #[cfg(test)]
+    #[cfg(any(test, feature = "sim"))]
     pub fn unset_mock_pt() {
         MOCK_PT.with(|f| *f.borrow_mut() = None)
     }
@@ -167,4 +177,13 @@ mod tests {
         assert_eq!(hlc2.c(), 0);
         Hlc::unset_mock_pt();
     }
+
+    #[test]
+    fn test_next_saturates() {
+        Hlc::set_mock_pt(1_628_999_999_946_752); // Hlc-friendly time is divisible by 0x1_0000
+        let max = Hlc::from_u64(u64::MAX);
+        assert_eq!(max.next(), max);
+        assert_eq!(max.inc(), max);
+        Hlc::unset_mock_pt();
+    }
 }
@@ -0,0 +1,194 @@
+//! Ed25519 peer identities.
+//!
+//! A signed peer's ID is the hex-encoded public key of its [`Identity`], so the ID alone
+//! is enough to verify its writes. Each insert is signed by its author over the author,
+//! HLC, key and value, and the signature is stored alongside the entry so that relays
+//! forward data that its recipients can still verify. Opsets are also signed by their
+//! sender. Deletes and bookmarks are not signed, since any peer may delete any entry.
+
+use bytes::Bytes;
+use ed25519_dalek::{Signature, Signer as _, SigningKey, VerifyingKey};
+
+use crate::{diff::Insert, hlc::Hlc, opset::OpSet, peer_id::PeerId};
+
+/// Ed25519 key pair identifying a peer
+pub struct Identity {
+    key: SigningKey,
+}
+
+/// Signs local writes and verifies remote writes,
+/// for stores whose keys and values are byte strings
+pub(crate) struct Signer<K, V> {
+    identity: Identity,
+    peer_id: PeerId,
+    key_bytes: fn(&K) -> &[u8],
+    value_bytes: fn(&V) -> &[u8],
+}
+
+impl Identity {
+    /// Generates a random identity
+    pub fn generate() -> Self {
+        Self::from_secret(&rand::random())
+    }
+
+    /// Restores an identity from its secret key
+    pub fn from_secret(secret: &[u8; 32]) -> Self {
+        Identity {
+            key: SigningKey::from_bytes(secret),
+        }
+    }
+
+    /// Returns the secret key
+    pub fn secret(&self) -> [u8; 32] {
+        self.key.to_bytes()
+    }
+
+    /// Returns the peer ID, derived from the public key
+    pub fn id(&self) -> String {
+        self.key
+            .verifying_key()
+            .as_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+
+    fn sign(&self, message: &[u8]) -> Bytes {
+        Bytes::copy_from_slice(&self.key.sign(message).to_bytes())
+    }
+}
+
+impl<K, V> Signer<K, V> {
+    pub fn new(identity: Identity) -> Self
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        Signer {
+            peer_id: PeerId::from_str(&identity.id()),
+            identity,
+            key_bytes: <K as AsRef<[u8]>>::as_ref,
+            value_bytes: <V as AsRef<[u8]>>::as_ref,
+        }
+    }
+
+    pub fn peer_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+
+    /// Signs a local insert
+    pub fn sign_insert(&self, key: &K, value: &V, hlc: Hlc) -> Bytes {
+        let message = insert_message(
+            &self.peer_id,
+            hlc,
+            (self.key_bytes)(key),
+            (self.value_bytes)(value),
+        );
+        self.identity.sign(&message)
+    }
+
+    /// Verifies that an insert is signed by its author
+    pub fn verify_insert(&self, author: &PeerId, insert: &Insert<K, V>) -> bool {
+        let message = insert_message(
+            author,
+            insert.hlc,
+            (self.key_bytes)(&insert.key),
+            (self.value_bytes)(&insert.value),
+        );
+        insert
+            .signature
+            .as_ref()
+            .is_some_and(|signature| verify(author, &message, signature))
+    }
+
+    /// Signs a local opset, replacing any previous signature
+    pub fn sign_opset(&self, opset: &mut OpSet<K, V>) {
+        opset.signature = Some(self.identity.sign(&opset_message(opset)));
+    }
+
+    /// Verifies that an opset is signed by its sender
+    pub fn verify_opset(&self, opset: &OpSet<K, V>) -> bool {
+        opset
+            .signature
+            .as_ref()
+            .is_some_and(|signature| verify(&opset.peer_id, &opset_message(opset), signature))
+    }
+}
+
+// Verifies a signature against the public key encoded in the peer ID
+fn verify(peer_id: &PeerId, message: &[u8], signature: &[u8]) -> bool {
+    let Some(public_key) = decode_hex(peer_id.as_slice()) else {
+        return false;
+    };
+    let Ok(key) = VerifyingKey::from_bytes(&public_key) else {
+        return false;
+    };
+    let Ok(signature) = Signature::from_slice(signature) else {
+        return false;
+    };
+    key.verify_strict(message, &signature).is_ok()
+}
+
+fn decode_hex(hex: &[u8]) -> Option<[u8; 32]> {
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut bytes = [0; 32];
+    for (byte, pair) in bytes.iter_mut().zip(hex.chunks_exact(2)) {
+        let pair = std::str::from_utf8(pair).ok()?;
+        *byte = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+// Signed insert message: author, HLC, key and value
+fn insert_message(author: &PeerId, hlc: Hlc, key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut message = b"cubby insert\0".to_vec();
+    push_bytes(&mut message, author.as_slice());
+    message.extend_from_slice(&hlc.to_u64().to_be_bytes());
+    push_bytes(&mut message, key);
+    push_bytes(&mut message, value);
+    message
+}
+
+// Signed opset message: sender, insert HLCs and signatures, deletes ordered by peer,
+// and transaction boundaries
+fn opset_message<K, V>(opset: &OpSet<K, V>) -> Vec<u8> {
+    let mut message = b"cubby opset\0".to_vec();
+    push_bytes(&mut message, opset.peer_id.as_slice());
+
+    message.extend_from_slice(&(opset.inserts.len() as u64).to_be_bytes());
+    for insert in &opset.inserts {
+        message.extend_from_slice(&insert.hlc.to_u64().to_be_bytes());
+        push_bytes(
+            &mut message,
+            insert.signature.as_deref().unwrap_or_default(),
+        );
+    }
+
+    let mut deletes: Vec<_> = opset.deletes.iter().collect();
+    deletes.sort_unstable_by_key(|(peer_id, _)| *peer_id);
+    message.extend_from_slice(&(deletes.len() as u64).to_be_bytes());
+    for (peer_id, bitmap) in deletes {
+        push_bytes(&mut message, peer_id.as_slice());
+        let mut bytes = Vec::with_capacity(bitmap.serialized_size());
+        bitmap
+            .serialize_into(&mut bytes)
+            .expect("writing to a Vec cannot fail");
+        push_bytes(&mut message, &bytes);
+    }
+
+    message.extend_from_slice(&(opset.txns.len() as u64).to_be_bytes());
+    for txn in &opset.txns {
+        message.extend_from_slice(&txn.start.to_u64().to_be_bytes());
+        message.extend_from_slice(&txn.end.to_u64().to_be_bytes());
+        message.push(txn.deletes as u8);
+    }
+    message
+}
+
+// Appends length-prefixed bytes
+fn push_bytes(message: &mut Vec<u8>, bytes: &[u8]) {
+    message.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+    message.extend_from_slice(bytes);
+}
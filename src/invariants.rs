@@ -0,0 +1,135 @@
+use std::fmt;
+
+/// Invariant violations found by a store's `check_invariants`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub violations: Vec<Violation>,
+}
+
+/// A single broken peer state accounting invariant.
+/// Peers are identified by their public ID and HLCs by their raw `u64` value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// An entry's author has no peer state
+    UnknownAuthor { peer: Vec<u8>, hlc: u64 },
+    /// An entry's HLC is missing from its author's index
+    MissingIndex { peer: Vec<u8>, hlc: u64 },
+    /// An entry's HLC is missing from its author's key map, or maps to another key
+    MissingKey { peer: Vec<u8>, hlc: u64 },
+    /// An indexed HLC has no corresponding entry
+    OrphanedIndex { peer: Vec<u8>, hlc: u64 },
+    /// A key map HLC has no corresponding entry
+    OrphanedKey { peer: Vec<u8>, hlc: u64 },
+    /// Multiple entries share an author and HLC
+    DuplicateHlc { peer: Vec<u8>, hlc: u64 },
+    /// A local HLC exceeds the local bookmark
+    AboveBookmark {
+        peer: Vec<u8>,
+        hlc: u64,
+        bookmark: u64,
+    },
+    /// A transaction boundary has no remaining inserts
+    EmptyTxn { peer: Vec<u8>, start: u64, end: u64 },
+    /// A peer's cached diff request index does not match its index
+    StaleRequest { peer: Vec<u8> },
+    /// A stored roaring container hash does not match the container's HLCs
+    StaleHash { peer: Vec<u8>, container: u64 },
+}
+
+impl Report {
+    /// Returns `true` if no invariants are violated
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    pub(crate) fn push(&mut self, violation: Violation) {
+        self.violations.push(violation);
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_ok() {
+            return write!(f, "no invariant violations");
+        }
+        write!(f, "{} invariant violation(s)", self.violations.len())?;
+        for violation in &self.violations {
+            write!(f, "\n- {violation}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::UnknownAuthor { peer, hlc } => {
+                write!(
+                    f,
+                    "entry {} has unknown author {}",
+                    hlc,
+                    peer.escape_ascii()
+                )
+            }
+            Violation::MissingIndex { peer, hlc } => {
+                write!(
+                    f,
+                    "entry {} is missing from the index of {}",
+                    hlc,
+                    peer.escape_ascii()
+                )
+            }
+            Violation::MissingKey { peer, hlc } => {
+                write!(
+                    f,
+                    "entry {} is missing from the keys of {}",
+                    hlc,
+                    peer.escape_ascii()
+                )
+            }
+            Violation::OrphanedIndex { peer, hlc } => {
+                write!(f, "index {} of {} has no entry", hlc, peer.escape_ascii())
+            }
+            Violation::OrphanedKey { peer, hlc } => {
+                write!(f, "key {} of {} has no entry", hlc, peer.escape_ascii())
+            }
+            Violation::DuplicateHlc { peer, hlc } => {
+                write!(
+                    f,
+                    "multiple entries share HLC {} of {}",
+                    hlc,
+                    peer.escape_ascii()
+                )
+            }
+            Violation::AboveBookmark {
+                peer,
+                hlc,
+                bookmark,
+            } => write!(
+                f,
+                "HLC {} of {} exceeds bookmark {}",
+                hlc,
+                peer.escape_ascii(),
+                bookmark
+            ),
+            Violation::EmptyTxn { peer, start, end } => write!(
+                f,
+                "transaction {}..={} of {} has no inserts",
+                start,
+                end,
+                peer.escape_ascii()
+            ),
+            Violation::StaleRequest { peer } => write!(
+                f,
+                "cached request index of {} is stale",
+                peer.escape_ascii()
+            ),
+            Violation::StaleHash { peer, container } => write!(
+                f,
+                "stored hash of container {} of {} is stale",
+                container,
+                peer.escape_ascii()
+            ),
+        }
+    }
+}
@@ -1,24 +1,95 @@
-use std::{collections::HashMap, io::Cursor, path::Path};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, hash_map::Entry},
+    io::Cursor,
+    ops::{
+        Bound::{Excluded, Unbounded},
+        RangeInclusive,
+    },
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use bytes::Bytes;
 use rand::distr::{Alphanumeric, SampleString};
-use roaring::RoaringTreemap;
+use roaring::{RoaringBitmap, RoaringTreemap};
 use rusqlite::{Connection, OptionalExtension};
+use serde::{
+    Serialize, Serializer,
+    ser::{Error as _, SerializeMap, SerializeSeq, SerializeStruct},
+};
 
-use crate::hlc::Hlc;
+use crate::{
+    diff::DiffRequest,
+    hlc::Hlc,
+    invariants::{Report, Violation},
+    peer_id::PeerId,
+    reconcile::{self, CONTAINER_SHIFT, HashRequest, HashResponse},
+};
 
 static SCHEMA_SQL: &str = include_str!("schema.sql");
 
+/// `PRAGMA user_version` of the schema in `schema.sql`.
+/// Version 0 databases store each peer bitmap as a single `bitmap_state` blob.
+const SCHEMA_VERSION: i32 = 1;
+
+// Savepoint IDs are unique across transactions, so that foreign savepoints are rejected
+static NEXT_SAVEPOINT: AtomicU64 = AtomicU64::new(0);
+
 /// Persisted key value store backed by SQLite
 pub struct KVStore {
     local: Peer,
     sqlite: Connection,
+    bitmaps: BitmapCache,
 }
 
 pub struct KVStoreTxn<'a> {
     sqlite: rusqlite::Transaction<'a>,
     local_id: i64,
     bookmark: &'a mut Hlc,
+    bitmaps: &'a mut BitmapCache,
+    inserts: RoaringTreemap,
+    deletes: HashMap<i64, RoaringTreemap>,
+    // committed metadata of each key the transaction wrote, as of its first write
+    committed: HashMap<Vec<u8>, Option<EntryMeta>>,
+    savepoints: Vec<SavepointState>,
+}
+
+/// Entry metadata identifying the write that produced an entry's current value.
+/// Used for optimistic concurrency checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryMeta {
+    peer_id: i64,
+    hlc: Hlc,
+}
+
+/// Diff that streams its entries from SQLite while it is serialized,
+/// so that serving it does not copy the payload into memory.
+/// It serializes like a [`Diff`] and decodes as a `Diff<Bytes, Bytes>`.
+///
+/// [`Diff`]: crate::diff::Diff
+pub struct DiffEncoder<'a> {
+    sqlite: &'a Connection,
+    peers: Vec<EncodedPeer>,
+}
+
+/// HLCs of a single peer's entries carried by an encoded diff
+struct EncodedPeer {
+    id: i64,
+    public_id: PeerId,
+    inserts: RoaringTreemap,
+    deletes: RoaringTreemap,
+    bookmark: Hlc,
+}
+
+/// Savepoint within a KVStore transaction, backed by an SQLite `SAVEPOINT`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Savepoint(u64);
+
+/// Transaction state captured when a savepoint is created
+struct SavepointState {
+    savepoint: Savepoint,
+    bookmark: Hlc,
     inserts: RoaringTreemap,
     deletes: HashMap<i64, RoaringTreemap>,
 }
@@ -29,6 +100,9 @@ struct Peer {
     bookmark: Hlc,
 }
 
+/// Deserialized bitmaps kept across commits, keyed by peer ID
+type BitmapCache = HashMap<i64, RoaringTreemap>;
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error(transparent)]
@@ -39,22 +113,242 @@ pub enum Error {
     MismatchedLocalId,
     #[error("cannot deserialize bitmap")]
     CannotDeserializeBitmap,
+    #[error("unsupported schema version {0}, expected at most {SCHEMA_VERSION}")]
+    UnsupportedSchemaVersion(i32),
+    #[error("savepoint has been released")]
+    ReleasedSavepoint,
+    #[error("entry metadata conflict")]
+    Conflict,
+    #[cfg(feature = "encryption")]
+    #[error(transparent)]
+    Crypt(#[from] crate::crypt::CryptError),
 }
 
 impl KVStore {
     /// Opens a KVStore at the path, with a provided local ID
     pub fn open_with_local_id<P: AsRef<Path>>(path: &P, local_id: &[u8]) -> Result<Self, Error> {
-        let sqlite = Connection::open(path)?;
-        let local = setup(&sqlite, Some(local_id))?;
-        Ok(KVStore { local, sqlite })
+        let mut sqlite = Connection::open(path)?;
+        let local = setup(&mut sqlite, Some(local_id))?;
+        Ok(KVStore {
+            local,
+            sqlite,
+            bitmaps: BitmapCache::default(),
+        })
     }
 
     /// Opens a KVStore at the path.
     /// If the store is new, a random local ID will be assigned.
     pub fn open<P: AsRef<Path>>(path: &P) -> Result<Self, Error> {
-        let sqlite = Connection::open(path)?;
-        let local = setup(&sqlite, None)?;
-        Ok(KVStore { local, sqlite })
+        let mut sqlite = Connection::open(path)?;
+        let local = setup(&mut sqlite, None)?;
+        Ok(KVStore {
+            local,
+            sqlite,
+            bitmaps: BitmapCache::default(),
+        })
+    }
+
+    /// Cross-checks `bitmap_containers` against `entries`, returning a report of every violation:
+    /// - every entry's HLC appears in its author's bitmap, and no two entries share one
+    /// - every bitmap HLC has an entry
+    /// - each peer's bookmark bounds all of its HLCs
+    /// - each stored container hash matches the container's HLCs
+    ///
+    /// The check reads every entry, so it is meant for tests and diagnostics.
+    pub fn check_invariants(&self) -> Result<Report, Error> {
+        let mut report = Report::default();
+        let peers = fetch_peers(&self.sqlite)?;
+        let public_id = |peer_id: i64| match peers.get(&peer_id) {
+            Some(peer) => peer.public_id.to_vec(),
+            None => format!("#{peer_id}").into_bytes(),
+        };
+
+        // rebuild each peer's bitmap from its entries
+        let (mut expected, duplicates) = fetch_entry_bitmaps(&self.sqlite)?;
+        for (peer_id, bitmap) in &expected {
+            if !peers.contains_key(peer_id) {
+                for hlc in bitmap {
+                    report.push(Violation::UnknownAuthor {
+                        peer: public_id(*peer_id),
+                        hlc,
+                    });
+                }
+            }
+        }
+        for (peer_id, hlc) in duplicates {
+            report.push(Violation::DuplicateHlc {
+                peer: public_id(peer_id),
+                hlc,
+            });
+        }
+
+        // compare against the stored bitmaps
+        let mut peer_ids = fetch_bitmap_peer_ids(&self.sqlite)?;
+        peer_ids.extend(expected.keys());
+        peer_ids.sort_unstable();
+        peer_ids.dedup();
+        for peer_id in peer_ids {
+            let stored = fetch_bitmap(&self.sqlite, peer_id)?;
+            let expected = expected.remove(&peer_id).unwrap_or_default();
+            for hlc in &expected - &stored {
+                report.push(Violation::MissingIndex {
+                    peer: public_id(peer_id),
+                    hlc,
+                });
+            }
+            for hlc in &stored - &expected {
+                report.push(Violation::OrphanedIndex {
+                    peer: public_id(peer_id),
+                    hlc,
+                });
+            }
+            if let Some(max) = expected.max().max(stored.max())
+                && let Some(peer) = peers.get(&peer_id)
+                && max > peer.bookmark.to_u64()
+            {
+                report.push(Violation::AboveBookmark {
+                    peer: public_id(peer_id),
+                    hlc: max,
+                    bookmark: peer.bookmark.to_u64(),
+                });
+            }
+        }
+        for (peer_id, container) in fetch_stale_hashes(&self.sqlite)? {
+            report.push(Violation::StaleHash {
+                peer: public_id(peer_id),
+                container,
+            });
+        }
+
+        Ok(report)
+    }
+
+    /// Reports the discrepancies between `bitmap_containers`, bookmarks and `entries`
+    /// that [`KVStore::rebuild_indexes`] would repair, without writing
+    pub fn verify_indexes(&self) -> Result<Report, Error> {
+        let mut report = self.check_invariants()?;
+        report.violations.retain(|violation| {
+            matches!(
+                violation,
+                Violation::MissingIndex { .. }
+                    | Violation::OrphanedIndex { .. }
+                    | Violation::AboveBookmark { .. }
+                    | Violation::StaleHash { .. }
+            )
+        });
+        Ok(report)
+    }
+
+    /// Recomputes every peer's bitmap from `entries(peer_id, hlc)` and raises each peer's
+    /// bookmark to its max HLC, returning the discrepancies found before rebuilding.
+    /// Entries with unknown authors or duplicate HLCs cannot be repaired this way.
+    pub fn rebuild_indexes(&mut self) -> Result<Report, Error> {
+        let report = self.verify_indexes()?;
+        let sqlite = self.sqlite.transaction()?;
+
+        let (bitmaps, _) = fetch_entry_bitmaps(&sqlite)?;
+        let peers = fetch_peers(&sqlite)?;
+        sqlite.execute("DELETE FROM bitmap_containers", [])?;
+        for (peer_id, bitmap) in &bitmaps {
+            write_bitmap(&sqlite, *peer_id, bitmap)?;
+            if let Some(max) = bitmap.max()
+                && let Some(peer) = peers.get(peer_id)
+                && max > peer.bookmark.to_u64()
+            {
+                update_bookmark(&sqlite, *peer_id, Hlc::from_u64(max))?;
+            }
+        }
+        sqlite.commit()?;
+        self.bitmaps.clear();
+
+        let local = fetch_peer(&self.sqlite, self.local.id)?;
+        self.local.bookmark = self.local.bookmark.max(local.bookmark);
+        Ok(report)
+    }
+
+    /// Computes the HLCs of a diff from the request object, returning an encoder that
+    /// reads their entries once serialized.
+    /// Requested peers that are unknown locally are ignored.
+    pub fn diff_encoder(&self, request: &DiffRequest) -> Result<DiffEncoder<'_>, Error> {
+        let mut peers = Vec::new();
+        for peer in fetch_peers(&self.sqlite)?.into_values() {
+            let public_id = PeerId::from(peer.public_id);
+            let remote = request
+                .0
+                .get(&public_id)
+                .map(|request| (request, request.index.bitmap()));
+
+            // HLCs below both the remote index and bookmark cannot end up in the diff
+            let start = remote.as_ref().map_or(0, |(request, remote)| {
+                let above = request.bookmark.to_u64().saturating_add(1);
+                remote.min().map_or(above, |min| min.min(above))
+            });
+            let index = match self.bitmaps.get(&peer.id) {
+                Some(cached) => Cow::Borrowed(cached),
+                None => Cow::Owned(fetch_bitmap_range(&self.sqlite, peer.id, start..=u64::MAX)?),
+            };
+            let (inserts, deletes) = match &remote {
+                Some((request, remote)) => {
+                    let index = &*index;
+
+                    // inserts: all e ⊂ (local - remote) AND e > remote.max
+                    let mut inserts = index - &**remote;
+                    inserts.remove_range(0..=request.bookmark.to_u64());
+
+                    // deletes: all e ⊂ (remote - local) AND e ≤ local.max
+                    let mut deletes = &**remote - index;
+                    deletes.remove_range((Excluded(peer.bookmark.to_u64()), Unbounded));
+                    (inserts, deletes)
+                }
+                // inserts: all e ⊂ local
+                None => (index.into_owned(), RoaringTreemap::new()),
+            };
+
+            let bookmark_changed = remote
+                .as_ref()
+                .is_none_or(|(request, _)| request.bookmark < peer.bookmark);
+            if !inserts.is_empty() || !deletes.is_empty() || bookmark_changed {
+                peers.push(EncodedPeer {
+                    id: peer.id,
+                    public_id,
+                    inserts,
+                    deletes,
+                    bookmark: peer.bookmark,
+                });
+            }
+        }
+        Ok(DiffEncoder {
+            sqlite: &self.sqlite,
+            peers,
+        })
+    }
+
+    /// Lists the ranges of a reconciliation round whose hashes differ from the local state.
+    /// Ranges spanning whole containers are hashed from the stored container hashes,
+    /// without reading their bitmaps. See [`crate::reconcile`] for the protocol.
+    pub fn compare_hashes(&self, request: &HashRequest) -> Result<HashResponse, Error> {
+        let peers: HashMap<PeerId, i64> = fetch_peers(&self.sqlite)?
+            .into_values()
+            .map(|peer| (PeerId::from(peer.public_id), peer.id))
+            .collect();
+        reconcile::try_compare_hashes(
+            request,
+            |peer_id| peers.get(peer_id).copied(),
+            |peer_id, range| {
+                let hashes = fetch_container_hashes(&self.sqlite, *peer_id, range.containers())?;
+                match reconcile::container_child_hashes(range, hashes) {
+                    Some(hashes) => Ok(hashes),
+                    None => {
+                        let index = fetch_bitmap_range(
+                            &self.sqlite,
+                            *peer_id,
+                            range.start()..=range.end(),
+                        )?;
+                        Ok(reconcile::child_hashes(&index, range))
+                    }
+                }
+            },
+        )
     }
 
     /// Begins a transaction
@@ -64,8 +358,11 @@ impl KVStore {
             sqlite: self.sqlite.transaction()?,
             local_id: self.local.id,
             bookmark: &mut self.local.bookmark,
+            bitmaps: &mut self.bitmaps,
             inserts: RoaringTreemap::new(),
             deletes: HashMap::default(),
+            committed: HashMap::default(),
+            savepoints: Vec::default(),
         })
     }
 }
@@ -75,9 +372,64 @@ impl KVStoreTxn<'_> {
     pub fn get(&self, key: &[u8]) -> Result<Vec<u8>, Error> {
         Ok(self
             .sqlite
-            .query_row("SELECT value FROM entries WHERE key = ?", [key], |row| {
-                row.get(0)
-            })?)
+            .prepare_cached("SELECT value FROM entries WHERE key = ?")?
+            .query_row([key], |row| row.get(0))?)
+    }
+
+    /// Get all keys
+    pub fn keys(&self) -> Result<Vec<Vec<u8>>, Error> {
+        let mut statement = self.sqlite.prepare("SELECT key FROM entries")?;
+        let keys = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(keys)
+    }
+
+    /// Get the committed metadata for a key, ignoring the transaction's own writes
+    pub fn get_meta(&self, key: &[u8]) -> Result<Option<EntryMeta>, Error> {
+        if let Some(meta) = self.committed.get(key) {
+            return Ok(*meta);
+        }
+        Ok(self
+            .sqlite
+            .prepare_cached("SELECT peer_id, hlc FROM entries WHERE key = ?")?
+            .query_row([key], |row| {
+                let peer_id = row.get(0)?;
+                let hlc: i64 = row.get(1)?;
+                Ok(EntryMeta {
+                    peer_id,
+                    hlc: Hlc::from_u64(hlc as u64),
+                })
+            })
+            .optional()?)
+    }
+
+    /// Validate that the entry's committed metadata matches `expected`, where `None` expects
+    /// the key to be absent. The transaction's own writes are ignored, as in
+    /// `MemStoreTxn::validate`.
+    /// The check runs immediately and is not repeated at commit, since the SQLite
+    /// transaction isolates what it read from other connections until then.
+    pub fn validate(&self, key: &[u8], expected: Option<&EntryMeta>) -> Result<(), Error> {
+        if self.get_meta(key)?.as_ref() != expected {
+            return Err(Error::Conflict);
+        }
+        Ok(())
+    }
+
+    /// Insert a key value pair iff the entry's metadata matches `expected`
+    pub fn compare_and_swap(
+        &mut self,
+        key: &[u8],
+        expected: Option<&EntryMeta>,
+        value: &[u8],
+    ) -> Result<(), Error> {
+        self.validate(key, expected)?;
+        self.insert(key, value)
+    }
+
+    /// Insert a key value pair iff the key is absent from the committed state
+    pub fn insert_if_absent(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.compare_and_swap(key, None, value)
     }
 
     /// Insert a key value pair into the store
@@ -92,10 +444,11 @@ impl KVStoreTxn<'_> {
         self.inserts.insert(hlc.to_u64());
 
         // insert the new value
-        self.sqlite.execute(
-            "INSERT INTO entries (key, value, peer_id, hlc) VALUES (?1, ?2, ?3, ?4)",
-            (key, value, peer_id, hlc.to_u64()),
-        )?;
+        self.sqlite
+            .prepare_cached(
+                "INSERT INTO entries (key, value, peer_id, hlc) VALUES (?1, ?2, ?3, ?4)",
+            )?
+            .execute((key, value, peer_id, hlc.to_u64()))?;
 
         Ok(())
     }
@@ -105,17 +458,22 @@ impl KVStoreTxn<'_> {
         // remove the deleted entry if it exists
         let deleted_entry = self
             .sqlite
-            .query_row(
-                "DELETE FROM entries WHERE key = ? RETURNING peer_id, hlc",
-                [key],
-                |row| {
-                    let old_peer_id: i64 = row.get(0)?;
-                    let old_hlc: i64 = row.get(1)?;
-                    Ok((old_peer_id, old_hlc))
-                },
-            )
+            .prepare_cached("DELETE FROM entries WHERE key = ? RETURNING peer_id, hlc")?
+            .query_row([key], |row| {
+                let old_peer_id: i64 = row.get(0)?;
+                let old_hlc: i64 = row.get(1)?;
+                Ok((old_peer_id, old_hlc))
+            })
             .optional()?;
 
+        // remember the committed metadata on the key's first write
+        if let Entry::Vacant(entry) = self.committed.entry(key.to_vec()) {
+            entry.insert(deleted_entry.map(|(peer_id, hlc)| EntryMeta {
+                peer_id,
+                hlc: Hlc::from_u64(hlc as u64),
+            }));
+        }
+
         // mark the old value for `key` for deletion from peer state
         if let Some((peer_id, hlc)) = deleted_entry {
             let deletes = self.deletes.entry(peer_id).or_default();
@@ -124,42 +482,234 @@ impl KVStoreTxn<'_> {
         Ok(())
     }
 
-    /// Commit a series of inserts and deletes
-    pub fn commit(mut self) -> Result<(), Error> {
-        let sqlite: &Connection = &self.sqlite;
+    /// Creates a savepoint that the transaction can later roll back to
+    pub fn savepoint(&mut self) -> Result<Savepoint, Error> {
+        let savepoint = Savepoint(NEXT_SAVEPOINT.fetch_add(1, Ordering::Relaxed));
+        self.sqlite
+            .execute_batch(&format!("SAVEPOINT {}", savepoint.name()))?;
+        self.savepoints.push(SavepointState {
+            savepoint,
+            bookmark: *self.bookmark,
+            inserts: self.inserts.clone(),
+            deletes: self.deletes.clone(),
+        });
+        Ok(savepoint)
+    }
+
+    /// Rolls back all changes made since the savepoint was created.
+    /// The savepoint remains active; savepoints created after it are released.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), Error> {
+        let position = self.savepoint_position(savepoint)?;
+        self.sqlite
+            .execute_batch(&format!("ROLLBACK TO {}", savepoint.name()))?;
+        self.savepoints.truncate(position + 1);
+        let state = &self.savepoints[position];
+        *self.bookmark = state.bookmark;
+        self.inserts = state.inserts.clone();
+        self.deletes = state.deletes.clone();
+        Ok(())
+    }
+
+    /// Releases the savepoint and all savepoints created after it, keeping their changes
+    pub fn release(&mut self, savepoint: Savepoint) -> Result<(), Error> {
+        let position = self.savepoint_position(savepoint)?;
+        self.sqlite
+            .execute_batch(&format!("RELEASE {}", savepoint.name()))?;
+        self.savepoints.truncate(position);
+        Ok(())
+    }
 
-        // persist updated bookmark
-        update_bookmark(sqlite, self.local_id, *self.bookmark)?;
+    fn savepoint_position(&self, savepoint: Savepoint) -> Result<usize, Error> {
+        self.savepoints
+            .iter()
+            .position(|state| state.savepoint == savepoint)
+            .ok_or(Error::ReleasedSavepoint)
+    }
 
-        // update local bitmap
-        if !self.inserts.is_empty() || self.deletes.contains_key(&self.local_id) {
-            let mut local_bitmap = fetch_bitmap(&self.sqlite, self.local_id)?;
-            local_bitmap |= self.inserts;
-            if let Some(local_deletes) = self.deletes.remove(&self.local_id) {
-                local_bitmap -= local_deletes;
-            }
-            if local_bitmap.is_empty() {
-                delete_bitmap(sqlite, self.local_id)?;
-            } else {
-                upsert_bitmap(sqlite, self.local_id, &local_bitmap)?;
+    /// Commit a series of inserts and deletes.
+    /// Only the roaring containers of peer bitmaps that the changes touch are rewritten.
+    pub fn commit(self) -> Result<(), Error> {
+        let KVStoreTxn {
+            sqlite,
+            local_id,
+            bookmark,
+            bitmaps,
+            inserts,
+            deletes,
+            ..
+        } = self;
+        let mut changes: HashMap<i64, (RoaringTreemap, RoaringTreemap)> = deletes
+            .into_iter()
+            .map(|(peer_id, deletes)| (peer_id, (RoaringTreemap::new(), deletes)))
+            .collect();
+        if !inserts.is_empty() {
+            changes.entry(local_id).or_default().0 = inserts;
+        }
+
+        // cached bitmaps are updated in place, so drop them if the commit fails
+        let result = commit_changes(sqlite, local_id, *bookmark, bitmaps, &changes);
+        if result.is_err() {
+            for peer_id in changes.keys() {
+                bitmaps.remove(peer_id);
             }
         }
+        result
+    }
+}
+
+impl DiffEncoder<'_> {
+    /// Returns `true` if the diff carries no inserts or deletes
+    pub fn is_empty(&self) -> bool {
+        self.peers
+            .iter()
+            .all(|peer| peer.inserts.is_empty() && peer.deletes.is_empty())
+    }
+}
+
+impl Serialize for DiffEncoder<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_newtype_struct("Diff", &EncodedPeers(self))
+    }
+}
+
+/// Peer states of an encoded diff, keyed by public ID
+struct EncodedPeers<'a>(&'a DiffEncoder<'a>);
+
+impl Serialize for EncodedPeers<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.peers.len()))?;
+        for peer in &self.0.peers {
+            map.serialize_entry(&peer.public_id, &EncodedPeerState(self.0.sqlite, peer))?;
+        }
+        map.end()
+    }
+}
+
+/// Peer state of an encoded diff, laid out like `DiffPeerState`
+struct EncodedPeerState<'a>(&'a Connection, &'a EncodedPeer);
+
+impl Serialize for EncodedPeerState<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let EncodedPeerState(sqlite, peer) = *self;
+        let len = 1 + !peer.inserts.is_empty() as usize + !peer.deletes.is_empty() as usize;
+        let mut state = serializer.serialize_struct("DiffPeerState", len)?;
+        if peer.inserts.is_empty() {
+            state.skip_field("inserts")?;
+        } else {
+            state.serialize_field("inserts", &EncodedInserts(sqlite, peer))?;
+        }
+        if peer.deletes.is_empty() {
+            state.skip_field("deletes")?;
+        } else {
+            state.serialize_field("deletes", &peer.deletes)?;
+        }
+        state.serialize_field("bookmark", &peer.bookmark)?;
+
+        // transaction boundaries are not persisted
+        state.skip_field("txns")?;
+        state.end()
+    }
+}
+
+/// Inserts of an encoded diff, read from `entries(peer_id, hlc)` in HLC order
+struct EncodedInserts<'a>(&'a Connection, &'a EncodedPeer);
+
+impl Serialize for EncodedInserts<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let EncodedInserts(sqlite, peer) = *self;
+        let min = peer.inserts.min().unwrap_or_default();
+        let mut statement = sqlite
+            .prepare_cached(
+                "SELECT key, value, hlc FROM entries WHERE peer_id = ?1 AND hlc >= ?2 ORDER BY hlc",
+            )
+            .map_err(S::Error::custom)?;
+        let mut rows = statement
+            .query((peer.id, min as i64))
+            .map_err(S::Error::custom)?;
 
-        // update or delete bitmaps from other peers
-        for (peer_id, deletes) in self.deletes {
-            let mut bitmap = fetch_bitmap(sqlite, peer_id)?;
-            bitmap -= deletes;
-            if bitmap.is_empty() {
-                delete_bitmap(sqlite, peer_id)?;
-            } else {
-                upsert_bitmap(sqlite, peer_id, &bitmap)?;
+        let mut seq = serializer.serialize_seq(Some(peer.inserts.len() as usize))?;
+        let mut count = 0;
+        while let Some(row) = rows.next().map_err(S::Error::custom)? {
+            let hlc = row.get::<_, i64>(2).map_err(S::Error::custom)? as u64;
+            if !peer.inserts.contains(hlc) {
+                continue;
             }
+            let blob = |i| row.get_ref(i).and_then(|value| Ok(value.as_blob()?));
+            seq.serialize_element(&EncodedInsert {
+                key: blob(0).map_err(S::Error::custom)?,
+                value: blob(1).map_err(S::Error::custom)?,
+                hlc: Hlc::from_u64(hlc),
+            })?;
+            count += 1;
         }
 
-        // commit changes in SQLite
-        self.sqlite.commit()?;
+        // the sequence length was promised upfront
+        if count != peer.inserts.len() {
+            return Err(S::Error::custom("bitmap_containers does not match entries"));
+        }
+        seq.end()
+    }
+}
 
-        Ok(())
+/// Insert of an encoded diff, laid out like `Insert`
+struct EncodedInsert<'a> {
+    key: &'a [u8],
+    value: &'a [u8],
+    hlc: Hlc,
+}
+
+impl Serialize for EncodedInsert<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut insert = serializer.serialize_struct("Insert", 3)?;
+        insert.serialize_field("key", &Blob(self.key))?;
+        insert.serialize_field("value", &Blob(self.value))?;
+        insert.serialize_field("hlc", &self.hlc)?;
+        insert.skip_field("signature")?;
+        insert.end()
+    }
+}
+
+/// Byte string serialized like `Bytes`
+struct Blob<'a>(&'a [u8]);
+
+impl Serialize for Blob<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+fn commit_changes(
+    sqlite: rusqlite::Transaction<'_>,
+    local_id: i64,
+    bookmark: Hlc,
+    bitmaps: &mut BitmapCache,
+    changes: &HashMap<i64, (RoaringTreemap, RoaringTreemap)>,
+) -> Result<(), Error> {
+    // persist updated bookmark
+    update_bookmark(&sqlite, local_id, bookmark)?;
+
+    // update bitmaps
+    for (peer_id, (inserts, deletes)) in changes {
+        let bitmap = match bitmaps.entry(*peer_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(fetch_bitmap(&sqlite, *peer_id)?),
+        };
+        *bitmap |= inserts;
+        *bitmap -= deletes;
+        for container in containers(&(inserts | deletes)) {
+            write_container(&sqlite, *peer_id, container, bitmap)?;
+        }
+    }
+
+    // commit changes in SQLite
+    sqlite.commit()?;
+
+    Ok(())
+}
+
+impl Savepoint {
+    fn name(self) -> String {
+        format!("cubby_savepoint_{}", self.0)
     }
 }
 
@@ -170,9 +720,10 @@ fn random_public_id() -> Bytes {
         .into()
 }
 
-/// Sets up the schema and local peer if necessary, returning the local peer
-fn setup(sqlite: &Connection, public_id: Option<&[u8]>) -> Result<Peer, Error> {
+/// Sets up or migrates the schema and local peer if necessary, returning the local peer
+fn setup(sqlite: &mut Connection, public_id: Option<&[u8]>) -> Result<Peer, Error> {
     if schema_exists(sqlite)? {
+        migrate(sqlite)?;
         let local_peer_id = fetch_local_id(sqlite)?;
         let local_peer = fetch_peer(sqlite, local_peer_id)?;
         if let Some(public_id) = public_id
@@ -193,6 +744,8 @@ fn setup(sqlite: &Connection, public_id: Option<&[u8]>) -> Result<Peer, Error> {
             [public_id_slice],
             |row| row.get(0),
         )?;
+        sqlite.execute("INSERT INTO metadata (local_id) VALUES (?)", [id])?;
+        sqlite.pragma_update(None, "user_version", SCHEMA_VERSION)?;
         Ok(Peer {
             id,
             public_id,
@@ -201,11 +754,74 @@ fn setup(sqlite: &Connection, public_id: Option<&[u8]>) -> Result<Peer, Error> {
     }
 }
 
+/// Migrates an existing schema to `SCHEMA_VERSION`
+fn migrate(sqlite: &mut Connection) -> Result<(), Error> {
+    let version: i32 = sqlite.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    match version {
+        SCHEMA_VERSION => return Ok(()),
+        0 => {}
+        _ => return Err(Error::UnsupportedSchemaVersion(version)),
+    }
+
+    let sqlite = sqlite.transaction()?;
+    if table_exists(&sqlite, "bitmap_state")? {
+        // fold each peer's state blob and any delta rows into hashed containers
+        let mut bitmaps = HashMap::<i64, RoaringTreemap>::new();
+        let states: Vec<(i64, Vec<u8>)> = sqlite
+            .prepare("SELECT peer_id, state FROM bitmap_state")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        for (peer_id, state) in states {
+            bitmaps.insert(peer_id, deserialize_bitmap(&state)?);
+        }
+        if table_exists(&sqlite, "bitmap_deltas")? {
+            let deltas: Vec<(i64, Vec<u8>, Vec<u8>)> = sqlite
+                .prepare("SELECT peer_id, inserts, deletes FROM bitmap_deltas ORDER BY id")?
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+                .collect::<Result<_, _>>()?;
+            for (peer_id, inserts, deletes) in deltas {
+                let bitmap = bitmaps.entry(peer_id).or_default();
+                *bitmap |= deserialize_bitmap(&inserts)?;
+                *bitmap -= deserialize_bitmap(&deletes)?;
+            }
+        }
+
+        sqlite.execute_batch(
+            "CREATE TABLE bitmap_containers (
+                peer_id INTEGER NOT NULL,
+                container INTEGER NOT NULL,
+                state BLOB NOT NULL,
+                hash INTEGER NOT NULL,
+                PRIMARY KEY (peer_id, container)
+            ) WITHOUT ROWID;
+            DROP TABLE IF EXISTS bitmap_deltas;
+            DROP TABLE bitmap_state;",
+        )?;
+        for (peer_id, bitmap) in &bitmaps {
+            write_bitmap(&sqlite, *peer_id, bitmap)?;
+        }
+    }
+
+    // version 0 never wrote the metadata row, and the local peer was the first one inserted
+    sqlite.execute(
+        "INSERT INTO metadata (local_id) SELECT min(id) FROM peers WHERE NOT EXISTS (SELECT 1 FROM metadata)",
+        [],
+    )?;
+    sqlite.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    sqlite.commit()?;
+    Ok(())
+}
+
 /// Checks whether a schema exists
 fn schema_exists(sqlite: &Connection) -> Result<bool, Error> {
+    table_exists(sqlite, "metadata")
+}
+
+/// Checks whether a table exists
+fn table_exists(sqlite: &Connection, name: &str) -> Result<bool, Error> {
     Ok(sqlite.query_row(
-        "SELECT count(1) FROM sqlite_master WHERE name = 'metadata'",
-        [],
+        "SELECT count(1) FROM sqlite_master WHERE name = ?",
+        [name],
         |r| r.get(0),
     )?)
 }
@@ -232,43 +848,638 @@ fn fetch_peer(sqlite: &Connection, id: i64) -> Result<Peer, Error> {
     )?)
 }
 
+/// Fetch all peers, keyed by ID
+fn fetch_peers(sqlite: &Connection) -> Result<HashMap<i64, Peer>, Error> {
+    let mut statement = sqlite.prepare("SELECT id, public_id, bookmark FROM peers")?;
+    let peers = statement
+        .query_map([], |row| {
+            let id = row.get(0)?;
+            let raw_public_id = row.get_ref(1)?.as_blob()?;
+            let raw_hlc: i64 = row.get(2)?;
+            Ok((
+                id,
+                Peer {
+                    id,
+                    public_id: Bytes::copy_from_slice(raw_public_id),
+                    bookmark: Hlc::from_u64(raw_hlc as u64),
+                },
+            ))
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(peers)
+}
+
+/// Bitmaps keyed by peer ID
+type PeerBitmaps = HashMap<i64, RoaringTreemap>;
+
+/// Rebuild each peer's bitmap from its entries, also returning any duplicate HLCs
+fn fetch_entry_bitmaps(sqlite: &Connection) -> Result<(PeerBitmaps, Vec<(i64, u64)>), Error> {
+    let mut bitmaps = PeerBitmaps::default();
+    let mut duplicates = Vec::new();
+    let mut statement = sqlite.prepare("SELECT peer_id, hlc FROM entries")?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let peer_id: i64 = row.get(0)?;
+        let hlc = row.get::<_, i64>(1)? as u64;
+        if !bitmaps.entry(peer_id).or_default().insert(hlc) {
+            duplicates.push((peer_id, hlc));
+        }
+    }
+    Ok((bitmaps, duplicates))
+}
+
+/// Fetch the IDs of all peers with a stored bitmap
+fn fetch_bitmap_peer_ids(sqlite: &Connection) -> Result<Vec<i64>, Error> {
+    let mut statement = sqlite.prepare("SELECT DISTINCT peer_id FROM bitmap_containers")?;
+    let peer_ids = statement
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    Ok(peer_ids)
+}
+
 /// Fetch a peer bitmap
 fn fetch_bitmap(sqlite: &Connection, peer_id: i64) -> Result<RoaringTreemap, Error> {
-    sqlite
-        .query_row(
-            "SELECT state FROM bitmap_state WHERE peer_id = ?",
-            [peer_id],
-            |row| {
-                let bytes = row.get_ref(0)?.as_blob()?;
-                let cursor = Cursor::new(bytes);
-                Ok(RoaringTreemap::deserialize_from(cursor)
-                    .map_err(|_| Error::CannotDeserializeBitmap))
-            },
-        )
-        .optional()?
-        .unwrap_or_else(|| Ok(RoaringTreemap::default()))
+    fetch_bitmap_range(sqlite, peer_id, 0..=u64::MAX)
+}
+
+/// Fetch the HLCs of a peer bitmap within a range, reading only the containers it spans
+fn fetch_bitmap_range(
+    sqlite: &Connection,
+    peer_id: i64,
+    hlcs: RangeInclusive<u64>,
+) -> Result<RoaringTreemap, Error> {
+    let mut statement = sqlite.prepare_cached(
+        "SELECT container, state FROM bitmap_containers WHERE peer_id = ?1 AND container BETWEEN ?2 AND ?3 ORDER BY container",
+    )?;
+    let mut rows = statement.query((
+        peer_id,
+        (hlcs.start() >> CONTAINER_SHIFT) as i64,
+        (hlcs.end() >> CONTAINER_SHIFT) as i64,
+    ))?;
+    let mut bitmap = RoaringTreemap::new();
+    while let Some(row) = rows.next()? {
+        let start = (row.get::<_, i64>(0)? as u64) << CONTAINER_SHIFT;
+        let state =
+            deserialize_container(row.get_ref(1)?.as_blob().map_err(rusqlite::Error::from)?)?;
+        bitmap
+            .append(state.iter().map(|low| start | low as u64))
+            .map_err(|_| Error::CannotDeserializeBitmap)?;
+    }
+    bitmap.remove_range(..*hlcs.start());
+    bitmap.remove_range((Excluded(*hlcs.end()), Unbounded));
+    Ok(bitmap)
+}
+
+/// Fetch the `(container, hash)` pairs of a peer bitmap's containers within a range
+fn fetch_container_hashes(
+    sqlite: &Connection,
+    peer_id: i64,
+    containers: RangeInclusive<u64>,
+) -> Result<Vec<(u64, u64)>, Error> {
+    let mut statement = sqlite.prepare_cached(
+        "SELECT container, hash FROM bitmap_containers WHERE peer_id = ?1 AND container BETWEEN ?2 AND ?3 ORDER BY container",
+    )?;
+    let hashes = statement
+        .query_map(
+            (
+                peer_id,
+                *containers.start() as i64,
+                *containers.end() as i64,
+            ),
+            |row| Ok((row.get::<_, i64>(0)? as u64, row.get::<_, i64>(1)? as u64)),
+        )?
+        .collect::<Result<_, _>>()?;
+    Ok(hashes)
+}
+
+/// Fetch the `(peer ID, container)` pairs of every stored container whose hash
+/// does not match its HLCs
+fn fetch_stale_hashes(sqlite: &Connection) -> Result<Vec<(i64, u64)>, Error> {
+    let mut statement =
+        sqlite.prepare("SELECT peer_id, container, state, hash FROM bitmap_containers")?;
+    let mut rows = statement.query([])?;
+    let mut stale = Vec::new();
+    while let Some(row) = rows.next()? {
+        let peer_id: i64 = row.get(0)?;
+        let container = row.get::<_, i64>(1)? as u64;
+        let state =
+            deserialize_container(row.get_ref(2)?.as_blob().map_err(rusqlite::Error::from)?)?;
+        let start = container << CONTAINER_SHIFT;
+        if reconcile::hash_hlcs(state.iter().map(|low| start | low as u64))
+            != row.get::<_, i64>(3)? as u64
+        {
+            stale.push((peer_id, container));
+        }
+    }
+    Ok(stale)
+}
+
+/// Deserialize the low bits of a container's HLCs
+fn deserialize_bitmap(bytes: &[u8]) -> Result<RoaringTreemap, Error> {
+    RoaringTreemap::deserialize_from(Cursor::new(bytes)).map_err(|_| Error::CannotDeserializeBitmap)
+}
+
+fn deserialize_container(bytes: &[u8]) -> Result<RoaringBitmap, Error> {
+    let state = RoaringBitmap::deserialize_from(Cursor::new(bytes))
+        .map_err(|_| Error::CannotDeserializeBitmap)?;
+    if state.max().is_some_and(|low| low >> CONTAINER_SHIFT != 0) {
+        return Err(Error::CannotDeserializeBitmap);
+    }
+    Ok(state)
+}
+
+/// Returns the containers spanned by a bitmap, in order
+fn containers(bitmap: &RoaringTreemap) -> Vec<u64> {
+    let mut containers: Vec<u64> = bitmap.iter().map(|hlc| hlc >> CONTAINER_SHIFT).collect();
+    containers.dedup();
+    containers
 }
 
-/// Upsert a peer bitmap
-fn upsert_bitmap(sqlite: &Connection, peer_id: i64, bitmap: &RoaringTreemap) -> Result<(), Error> {
-    let mut bitmap_bytes = vec![];
-    bitmap.serialize_into(&mut bitmap_bytes)?;
-    sqlite.execute("INSERT INTO bitmap_state (peer_id, state) VALUES (?1, ?2) ON CONFLICT (peer_id) DO UPDATE SET state = ?2", (peer_id, &bitmap_bytes))?;
+/// Write a peer bitmap's HLCs within a container to its row, deleting the row if there are none
+fn write_container(
+    sqlite: &Connection,
+    peer_id: i64,
+    container: u64,
+    bitmap: &RoaringTreemap,
+) -> Result<(), Error> {
+    let start = container << CONTAINER_SHIFT;
+    let end = start | u64::MAX >> (64 - CONTAINER_SHIFT);
+    let mut hlcs = bitmap.iter();
+    hlcs.advance_to(start);
+    let state: RoaringBitmap = hlcs
+        .take_while(|hlc| *hlc <= end)
+        .map(|hlc| (hlc - start) as u32)
+        .collect();
+
+    if state.is_empty() {
+        sqlite
+            .prepare_cached("DELETE FROM bitmap_containers WHERE peer_id = ?1 AND container = ?2")?
+            .execute((peer_id, container as i64))?;
+    } else {
+        let hash = reconcile::hash_hlcs(state.iter().map(|low| start | low as u64));
+        let mut bytes = Vec::with_capacity(state.serialized_size());
+        state.serialize_into(&mut bytes)?;
+        sqlite
+            .prepare_cached("INSERT INTO bitmap_containers (peer_id, container, state, hash) VALUES (?1, ?2, ?3, ?4) ON CONFLICT (peer_id, container) DO UPDATE SET state = ?3, hash = ?4")?
+            .execute((peer_id, container as i64, bytes, hash as i64))?;
+    }
     Ok(())
 }
 
-/// Delete a peer bitmap
-fn delete_bitmap(sqlite: &Connection, peer_id: i64) -> Result<(), Error> {
-    sqlite.execute("DELETE FROM bitmap_state WHERE peer_id = ?", (peer_id,))?;
+/// Write every container of a peer bitmap
+fn write_bitmap(sqlite: &Connection, peer_id: i64, bitmap: &RoaringTreemap) -> Result<(), Error> {
+    for container in containers(bitmap) {
+        write_container(sqlite, peer_id, container, bitmap)?;
+    }
     Ok(())
 }
 
 /// Update a peer bookmark
 fn update_bookmark(sqlite: &Connection, peer_id: i64, bookmark: Hlc) -> Result<(), Error> {
     let bookmark = bookmark.to_u64() as i64;
-    sqlite.execute(
-        "UPDATE peers SET bookmark = ?2 WHERE id = ?1",
-        (peer_id, bookmark),
-    )?;
+    sqlite
+        .prepare_cached("UPDATE peers SET bookmark = ?2 WHERE id = ?1")?
+        .execute((peer_id, bookmark))?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "memory")]
+    use crate::reconcile::Reconciler;
+
+    #[test]
+    fn test_reopen() {
+        // keeps the shared in-memory database alive while the store is reopened
+        let path = "file:cubby-test-reopen?mode=memory&cache=shared";
+        let _sqlite = Connection::open(path).unwrap();
+        let mut store = KVStore::open_with_local_id(&path, b"kv").unwrap();
+        let mut txn = store.begin().unwrap();
+        txn.insert(b"a", b"1").unwrap();
+        txn.insert(b"b", b"2").unwrap();
+        txn.commit().unwrap();
+        let (id, bookmark) = (store.local.id, store.local.bookmark);
+        drop(store);
+
+        let mut store = KVStore::open(&path).unwrap();
+        assert_eq!(store.local.id, id);
+        assert_eq!(store.local.public_id, "kv");
+        assert_eq!(store.local.bookmark, bookmark);
+        assert!(store.check_invariants().unwrap().is_ok());
+        let txn = store.begin().unwrap();
+        assert_eq!(txn.get(b"a").unwrap(), b"1");
+        assert_eq!(txn.get(b"b").unwrap(), b"2");
+        drop(txn);
+        drop(store);
+
+        assert!(matches!(
+            KVStore::open_with_local_id(&path, b"other"),
+            Err(Error::MismatchedLocalId)
+        ));
+    }
+
+    /// Creates a version 0 store, whose bitmaps are whole treemaps in `bitmap_state`, with
+    /// the `bitmap_deltas` table of the delta layout if `deltas` is set
+    fn legacy_store(path: &str, deltas: bool) -> Connection {
+        let sqlite = Connection::open(path).unwrap();
+        sqlite
+            .execute_batch(
+                "CREATE TABLE metadata (local_id INTEGER NOT NULL);
+                CREATE TABLE peers (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    public_id BLOB NOT NULL UNIQUE,
+                    bookmark INTEGER NOT NULL
+                );
+                CREATE TABLE bitmap_state (
+                    peer_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    state BLOB NOT NULL
+                );
+                CREATE TABLE entries (
+                    key BLOB NOT NULL PRIMARY KEY,
+                    value BLOB NOT NULL,
+                    peer_id INTEGER NOT NULL,
+                    hlc INTEGER NOT NULL
+                );
+                INSERT INTO peers (public_id, bookmark)
+                    VALUES (CAST('kv' AS BLOB), 131072), (CAST('remote' AS BLOB), 327680);
+                INSERT INTO entries VALUES
+                    (CAST('a' AS BLOB), CAST('1' AS BLOB), 1, 65536),
+                    (CAST('b' AS BLOB), CAST('2' AS BLOB), 1, 131072),
+                    (CAST('c' AS BLOB), CAST('3' AS BLOB), 2, 327680);",
+            )
+            .unwrap();
+        let serialize = |hlcs: &[u64]| {
+            let mut bytes = vec![];
+            RoaringTreemap::from_iter(hlcs)
+                .serialize_into(&mut bytes)
+                .unwrap();
+            bytes
+        };
+        let insert_state = |peer_id: i64, hlcs: &[u64]| {
+            sqlite
+                .execute(
+                    "INSERT INTO bitmap_state (peer_id, state) VALUES (?1, ?2)",
+                    (peer_id, serialize(hlcs)),
+                )
+                .unwrap();
+        };
+        insert_state(1, &[1 << 16, 2 << 16]);
+        if deltas {
+            // the remote's entry only exists as a delta over a stale state
+            insert_state(2, &[4 << 16]);
+            sqlite
+                .execute_batch(
+                    "CREATE TABLE bitmap_deltas (
+                        id INTEGER PRIMARY KEY AUTOINCREMENT,
+                        peer_id INTEGER NOT NULL,
+                        inserts BLOB NOT NULL,
+                        deletes BLOB NOT NULL
+                    );",
+                )
+                .unwrap();
+            sqlite
+                .execute(
+                    "INSERT INTO bitmap_deltas (peer_id, inserts, deletes) VALUES (2, ?1, ?2)",
+                    (serialize(&[5 << 16]), serialize(&[4 << 16])),
+                )
+                .unwrap();
+        } else {
+            insert_state(2, &[5 << 16]);
+        }
+        sqlite
+    }
+
+    #[test]
+    fn test_migrate_legacy_bitmaps() {
+        for deltas in [false, true] {
+            let path = format!("file:cubby-test-migrate-{deltas}?mode=memory&cache=shared");
+            let sqlite = legacy_store(&path, deltas);
+
+            let mut store = KVStore::open_with_local_id(&path, b"kv").unwrap();
+            assert!(store.check_invariants().unwrap().is_ok());
+            assert_eq!(
+                fetch_bitmap(&store.sqlite, 2).unwrap(),
+                RoaringTreemap::from_iter([5 << 16])
+            );
+            let txn = store.begin().unwrap();
+            assert_eq!(txn.get(b"a").unwrap(), b"1");
+            assert_eq!(txn.get(b"c").unwrap(), b"3");
+            drop(txn);
+            drop(store);
+
+            let version: i32 = sqlite
+                .pragma_query_value(None, "user_version", |row| row.get(0))
+                .unwrap();
+            assert_eq!(version, SCHEMA_VERSION);
+            assert!(!table_exists(&sqlite, "bitmap_state").unwrap());
+            assert!(!table_exists(&sqlite, "bitmap_deltas").unwrap());
+            let store = KVStore::open(&path).unwrap();
+            assert_eq!(store.local.public_id, "kv");
+        }
+    }
+
+    #[test]
+    fn test_unsupported_schema_version() {
+        let path = "file:cubby-test-schema-version?mode=memory&cache=shared";
+        let sqlite = Connection::open(path).unwrap();
+        drop(KVStore::open(&path).unwrap());
+        sqlite
+            .pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+        assert!(matches!(
+            KVStore::open(&path),
+            Err(Error::UnsupportedSchemaVersion(version)) if version == SCHEMA_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn test_savepoints() {
+        let mut store = KVStore::open(&":memory:").unwrap();
+        let mut txn = store.begin().unwrap();
+        txn.insert(b"a", b"1").unwrap();
+        let outer = txn.savepoint().unwrap();
+        txn.insert(b"b", b"2").unwrap();
+        txn.delete(b"a").unwrap();
+        txn.rollback_to(outer).unwrap();
+        txn.insert(b"c", b"3").unwrap();
+        let nested = txn.savepoint().unwrap();
+        txn.insert(b"d", b"4").unwrap();
+        txn.release(nested).unwrap();
+        assert!(matches!(
+            txn.rollback_to(nested),
+            Err(Error::ReleasedSavepoint)
+        ));
+
+        assert_eq!(txn.get(b"a").unwrap(), b"1");
+        assert!(txn.get(b"b").is_err());
+        assert_eq!(txn.inserts.len(), 3);
+        assert!(txn.deletes.is_empty());
+        txn.commit().unwrap();
+
+        // a savepoint of another transaction is rejected
+        let mut txn = store.begin().unwrap();
+        txn.savepoint().unwrap();
+        assert!(matches!(
+            txn.rollback_to(outer),
+            Err(Error::ReleasedSavepoint)
+        ));
+        assert!(matches!(txn.release(outer), Err(Error::ReleasedSavepoint)));
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let mut store = KVStore::open(&":memory:").unwrap();
+        let mut txn = store.begin().unwrap();
+        txn.insert(b"a", b"1").unwrap();
+        txn.commit().unwrap();
+        let stale = store.begin().unwrap().get_meta(b"a").unwrap();
+        let mut txn = store.begin().unwrap();
+        txn.insert(b"a", b"2").unwrap();
+        txn.commit().unwrap();
+
+        // checks compare against the committed state, ignoring the transaction's own writes
+        let mut txn = store.begin().unwrap();
+        let meta = txn.get_meta(b"a").unwrap();
+        assert!(matches!(
+            txn.validate(b"a", stale.as_ref()),
+            Err(Error::Conflict)
+        ));
+        txn.insert(b"b", b"1").unwrap();
+        txn.insert_if_absent(b"b", b"2").unwrap();
+        txn.compare_and_swap(b"a", meta.as_ref(), b"3").unwrap();
+        txn.compare_and_swap(b"a", meta.as_ref(), b"4").unwrap();
+        txn.delete(b"a").unwrap();
+        txn.validate(b"a", meta.as_ref()).unwrap();
+        assert!(matches!(
+            txn.insert_if_absent(b"a", b"5"),
+            Err(Error::Conflict)
+        ));
+        txn.commit().unwrap();
+        assert!(store.begin().unwrap().get(b"a").is_err());
+        assert_eq!(store.begin().unwrap().get(b"b").unwrap(), b"2");
+    }
+
+    #[cfg(feature = "memory")]
+    #[test]
+    fn test_diff_encoder() {
+        use crate::{diff::Diff, memory::MemStore};
+
+        let mut store = KVStore::open_with_local_id(&":memory:", b"alice").unwrap();
+        let mut txn = store.begin().unwrap();
+        txn.insert(b"a", b"1").unwrap();
+        txn.insert(b"b", b"2").unwrap();
+        txn.insert(b"c", b"3").unwrap();
+        txn.commit().unwrap();
+
+        let mut replica: MemStore<Bytes, Bytes> = MemStore::new("bob");
+        let sync = |store: &KVStore, replica: &mut MemStore<Bytes, Bytes>| {
+            let encoder = store.diff_encoder(&replica.request_diff()).unwrap();
+            let mut bytes = Vec::new();
+            ciborium::into_writer(&encoder, &mut bytes).unwrap();
+            let diff: Diff<Bytes, Bytes> = ciborium::from_reader(&bytes[..]).unwrap();
+            replica.integrate_diff(diff);
+        };
+
+        // entries are streamed to a replica that knows nothing
+        sync(&store, &mut replica);
+        assert_eq!(replica.len(), 3);
+        assert_eq!(replica.get(&Bytes::from_static(b"b")).unwrap(), &b"2"[..]);
+        assert!(
+            store
+                .diff_encoder(&replica.request_diff())
+                .unwrap()
+                .is_empty()
+        );
+
+        // only new entries and deletes are streamed afterwards
+        let mut txn = store.begin().unwrap();
+        txn.insert(b"a", b"4").unwrap();
+        txn.delete(b"c").unwrap();
+        txn.commit().unwrap();
+        let encoder = store.diff_encoder(&replica.request_diff()).unwrap();
+        let peer = &encoder.peers[0];
+        assert_eq!((peer.inserts.len(), peer.deletes.len()), (1, 2));
+        sync(&store, &mut replica);
+        let entries: Vec<_> = replica
+            .entries()
+            .iter()
+            .map(|(key, _)| key.clone())
+            .collect();
+        assert_eq!(entries, [&b"a"[..], &b"b"[..]]);
+        assert_eq!(replica.get(&Bytes::from_static(b"a")).unwrap(), &b"4"[..]);
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        let mut store = KVStore::open(&":memory:").unwrap();
+        let mut txn = store.begin().unwrap();
+        txn.insert(b"a", b"1").unwrap();
+        txn.insert(b"b", b"2").unwrap();
+        txn.insert(b"a", b"3").unwrap();
+        txn.commit().unwrap();
+        assert!(store.check_invariants().unwrap().is_ok());
+
+        // an entry written behind the store's back is missing from the bitmap
+        store
+            .sqlite
+            .execute(
+                "INSERT INTO entries (key, value, peer_id, hlc) VALUES (?1, ?2, ?3, ?4)",
+                (b"c", b"4", store.local.id, 1),
+            )
+            .unwrap();
+        let report = store.check_invariants().unwrap();
+        assert_eq!(
+            report.violations,
+            vec![Violation::MissingIndex {
+                peer: store.local.public_id.to_vec(),
+                hlc: 1,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_rebuild_indexes() {
+        let mut store = KVStore::open(&":memory:").unwrap();
+        let mut txn = store.begin().unwrap();
+        txn.insert(b"a", b"1").unwrap();
+        txn.insert(b"b", b"2").unwrap();
+        txn.commit().unwrap();
+
+        // drop the bitmap and write an entry past the bookmark
+        let local_id = store.local.id;
+        let hlc = store.local.bookmark.to_u64() + 10;
+        store
+            .sqlite
+            .execute(
+                "DELETE FROM bitmap_containers WHERE peer_id = ?",
+                [local_id],
+            )
+            .unwrap();
+        store
+            .sqlite
+            .execute(
+                "INSERT INTO entries (key, value, peer_id, hlc) VALUES (?1, ?2, ?3, ?4)",
+                (b"c", b"3", local_id, hlc as i64),
+            )
+            .unwrap();
+
+        let verified = store.verify_indexes().unwrap();
+        assert_eq!(verified.violations.len(), 4);
+        assert_eq!(store.verify_indexes().unwrap(), verified);
+
+        let repaired = store.rebuild_indexes().unwrap();
+        assert_eq!(repaired, verified);
+        assert!(store.check_invariants().unwrap().is_ok());
+        assert_eq!(store.local.bookmark.to_u64(), hlc);
+        assert_eq!(fetch_bitmap(&store.sqlite, local_id).unwrap().len(), 3);
+    }
+
+    // Commits two entries in each of the containers of `pts`, keyed by their position
+    fn store_with_containers(pts: &[u64]) -> KVStore {
+        let mut store = KVStore::open(&":memory:").unwrap();
+        for (i, pt) in pts.iter().enumerate() {
+            Hlc::set_mock_pt(pt << CONTAINER_SHIFT);
+            let mut txn = store.begin().unwrap();
+            txn.insert(&[i as u8, (i >> 8) as u8, 0], b"1").unwrap();
+            txn.insert(&[i as u8, (i >> 8) as u8, 1], b"2").unwrap();
+            txn.commit().unwrap();
+        }
+        Hlc::unset_mock_pt();
+        store
+    }
+
+    #[test]
+    fn test_bitmap_containers() {
+        let pts = [1, 2, 3, 1 << 20, (1 << 20) + 1];
+        let mut store = store_with_containers(&pts);
+        let local_id = store.local.id;
+        let hashes =
+            fetch_container_hashes(&store.sqlite, local_id, 0..=u64::MAX >> CONTAINER_SHIFT)
+                .unwrap();
+        let containers: Vec<u64> = hashes.iter().map(|(container, _)| *container).collect();
+        assert_eq!(containers, pts);
+
+        // stored hashes match the HLCs, and the cache matches the rows
+        let bitmap = fetch_bitmap(&store.sqlite, local_id).unwrap();
+        assert_eq!(bitmap.len(), 10);
+        assert_eq!(bitmap, store.bitmaps[&local_id]);
+        for (container, hash) in hashes {
+            let hlcs: RoaringTreemap = bitmap
+                .iter()
+                .filter(|hlc| hlc >> CONTAINER_SHIFT == container)
+                .collect();
+            assert_eq!(reconcile::hash_hlcs(&hlcs), hash);
+            let start = container << CONTAINER_SHIFT;
+            let range = start..=start | 0xffff;
+            assert_eq!(
+                fetch_bitmap_range(&store.sqlite, local_id, range).unwrap(),
+                hlcs
+            );
+        }
+
+        // emptied containers are deleted
+        let mut txn = store.begin().unwrap();
+        txn.delete(&[1, 0, 0]).unwrap();
+        txn.delete(&[1, 0, 1]).unwrap();
+        txn.commit().unwrap();
+        let hashes =
+            fetch_container_hashes(&store.sqlite, local_id, 0..=u64::MAX >> CONTAINER_SHIFT)
+                .unwrap();
+        assert_eq!(hashes.len(), 4);
+        assert!(store.check_invariants().unwrap().is_ok());
+
+        // stale hashes are reported and repaired
+        store
+            .sqlite
+            .execute(
+                "UPDATE bitmap_containers SET hash = hash + 1 WHERE container = 3",
+                [],
+            )
+            .unwrap();
+        assert_eq!(
+            store.verify_indexes().unwrap().violations,
+            vec![Violation::StaleHash {
+                peer: store.local.public_id.to_vec(),
+                container: 3,
+            }]
+        );
+        store.rebuild_indexes().unwrap();
+        assert!(store.check_invariants().unwrap().is_ok());
+    }
+
+    #[cfg(feature = "memory")]
+    #[test]
+    fn test_compare_hashes() {
+        // two buckets of over a hundred HLCs, the first spanning two containers
+        let pts: Vec<u64> = (0..300).map(|i| i / 100 * 5 + ((i / 200) << 20)).collect();
+        let store = store_with_containers(&pts);
+        let peer_id = PeerId::from(store.local.public_id.clone());
+        let index = fetch_bitmap(&store.sqlite, store.local.id).unwrap();
+
+        // the remote misses a few HLCs and holds one the store has deleted
+        let mut remote = index.clone();
+        for hlc in index.iter().step_by(9) {
+            remote.remove(hlc);
+        }
+        remote.insert((5 << CONTAINER_SHIFT) + 3);
+
+        let mut reconciler = Reconciler::new([(&peer_id, &remote, store.local.bookmark)]);
+        let mut request = reconciler.request();
+        let mut rounds = 1;
+        loop {
+            let response = store.compare_hashes(&request).unwrap();
+            assert_eq!(
+                response,
+                reconcile::compare_hashes(&request, |_| Some(&index))
+            );
+            assert!(!response.0.is_empty());
+            match reconciler.refine(response) {
+                Some(next) => request = next,
+                None => break,
+            }
+            rounds += 1;
+        }
+        assert_eq!(rounds, 4);
+    }
+}
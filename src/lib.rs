@@ -1,10 +1,23 @@
 #![doc = include_str!("../README.md")]
 
+pub mod access;
+#[cfg(feature = "encryption")]
+pub mod crypt;
 pub mod diff;
+pub mod filter;
 mod hlc;
+#[cfg(feature = "signing")]
+pub mod identity;
+pub mod invariants;
 #[cfg(feature = "kv")]
 pub mod kv;
+pub mod limits;
 #[cfg(feature = "memory")]
 pub mod memory;
+#[cfg(all(test, feature = "memory"))]
+mod model;
 pub mod opset;
 mod peer_id;
+pub mod reconcile;
+#[cfg(all(feature = "memory", any(test, feature = "sim")))]
+pub mod sim;
@@ -0,0 +1,265 @@
+//! Resource limits on incoming sync traffic.
+//!
+//! Messages from semi-trusted peers should have their length checked with
+//! [`Limits::check_message`] before they are deserialized, since the other checks only
+//! see fully decoded messages. Decoded messages are then checked with
+//! [`Limits::check_request`], [`Limits::check_filtered_request`], [`Limits::check_diff`]
+//! or [`Limits::check_opset`] before they reach a store. [`MemStore`] also enforces
+//! its limits in the `try_build_*` and `try_integrate_*` methods.
+//!
+//! [`MemStore`]: crate::memory::MemStore
+
+use bytes::Bytes;
+
+use crate::{
+    diff::{Diff, DiffRequest, FilteredDiffRequest, Insert},
+    filter::KeyFilter,
+    opset::OpSet,
+};
+
+/// Resource limits on incoming sync traffic.
+/// The default limits are unbounded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum size of an encoded message, in bytes
+    pub max_message_bytes: usize,
+    /// Maximum serialized size of a diff request's bitmaps, in bytes
+    pub max_request_bytes: usize,
+    /// Maximum number of peers in a diff request, diff or opset
+    pub max_peers: usize,
+    /// Maximum number of inserts and deletes in a diff or opset
+    pub max_entries: u64,
+    /// Maximum size of a diff or opset's keys, values and delete bitmaps, in bytes
+    pub max_bytes: usize,
+    /// Maximum size of a single key, in bytes
+    pub max_key_size: usize,
+    /// Maximum size of a single value, in bytes
+    pub max_value_size: usize,
+    /// Maximum number of key ranges in a filtered diff request
+    pub max_filter_ranges: usize,
+}
+
+/// Error returned when a sync message exceeds a limit
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum LimitError {
+    #[error("message of {bytes} bytes exceeds limit {limit}")]
+    MessageTooLarge { bytes: usize, limit: usize },
+    #[error("request bitmaps of {bytes} bytes exceed limit {limit}")]
+    RequestTooLarge { bytes: usize, limit: usize },
+    #[error("{peers} peers exceed limit {limit}")]
+    TooManyPeers { peers: usize, limit: usize },
+    #[error("{entries} entries exceed limit {limit}")]
+    TooManyEntries { entries: u64, limit: u64 },
+    #[error("{bytes} bytes exceed limit {limit}")]
+    TooManyBytes { bytes: usize, limit: usize },
+    #[error("key of {size} bytes exceeds limit {limit}")]
+    KeyTooLarge { size: usize, limit: usize },
+    #[error("value of {size} bytes exceeds limit {limit}")]
+    ValueTooLarge { size: usize, limit: usize },
+    #[error("{ranges} filter ranges exceed limit {limit}")]
+    TooManyRanges { ranges: usize, limit: usize },
+}
+
+/// Size of a key or value, in bytes, as counted against [`Limits`]
+pub trait ByteLen {
+    fn byte_len(&self) -> usize;
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_message_bytes: usize::MAX,
+            max_request_bytes: usize::MAX,
+            max_peers: usize::MAX,
+            max_entries: u64::MAX,
+            max_bytes: usize::MAX,
+            max_key_size: usize::MAX,
+            max_value_size: usize::MAX,
+            max_filter_ranges: usize::MAX,
+        }
+    }
+}
+
+impl Limits {
+    /// Checks an encoded message's length, before it is deserialized
+    pub fn check_message(&self, message: &[u8]) -> Result<(), LimitError> {
+        if message.len() > self.max_message_bytes {
+            return Err(LimitError::MessageTooLarge {
+                bytes: message.len(),
+                limit: self.max_message_bytes,
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks a diff request's peer count and bitmap size
+    pub fn check_request(&self, request: &DiffRequest) -> Result<(), LimitError> {
+        self.check_peers(request.0.len())?;
+        let bytes = request.index_size();
+        if bytes > self.max_request_bytes {
+            return Err(LimitError::RequestTooLarge {
+                bytes,
+                limit: self.max_request_bytes,
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks a filtered diff request's range count, peer count and bitmap size
+    pub fn check_filtered_request<K>(
+        &self,
+        request: &FilteredDiffRequest<K>,
+    ) -> Result<(), LimitError> {
+        self.check_filter(&request.filter)?;
+        self.check_request(&request.request)
+    }
+
+    /// Checks a diff's peer count, entry count, size, and key and value sizes
+    pub fn check_diff<K: ByteLen, V: ByteLen>(&self, diff: &Diff<K, V>) -> Result<(), LimitError> {
+        self.check_peers(diff.0.len())?;
+        let entries = diff
+            .0
+            .values()
+            .map(|state| state.inserts.len() as u64 + state.deletes.len())
+            .sum();
+        self.check_entries(entries)?;
+
+        let mut bytes = 0;
+        for state in diff.0.values() {
+            bytes += self.check_inserts(&state.inserts)?;
+            bytes += state.deletes.serialized_size();
+        }
+        self.check_bytes(bytes)
+    }
+
+    /// Checks an opset's peer count, entry count, size, and key and value sizes
+    pub fn check_opset<K: ByteLen, V: ByteLen>(
+        &self,
+        opset: &OpSet<K, V>,
+    ) -> Result<(), LimitError> {
+        let authors = (!opset.deletes.contains_key(&opset.peer_id)) as usize;
+        self.check_peers(opset.deletes.len() + authors)?;
+        let entries = opset.inserts.len() as u64
+            + opset
+                .deletes
+                .values()
+                .map(|deletes| deletes.len())
+                .sum::<u64>();
+        self.check_entries(entries)?;
+
+        let mut bytes = self.check_inserts(&opset.inserts)?;
+        for deletes in opset.deletes.values() {
+            bytes += deletes.serialized_size();
+        }
+        self.check_bytes(bytes)
+    }
+
+    pub(crate) fn check_filter<K>(&self, filter: &KeyFilter<K>) -> Result<(), LimitError> {
+        let ranges = filter.len();
+        if ranges > self.max_filter_ranges {
+            return Err(LimitError::TooManyRanges {
+                ranges,
+                limit: self.max_filter_ranges,
+            });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_entries(&self, entries: u64) -> Result<(), LimitError> {
+        if entries > self.max_entries {
+            return Err(LimitError::TooManyEntries {
+                entries,
+                limit: self.max_entries,
+            });
+        }
+        Ok(())
+    }
+
+    fn check_peers(&self, peers: usize) -> Result<(), LimitError> {
+        if peers > self.max_peers {
+            return Err(LimitError::TooManyPeers {
+                peers,
+                limit: self.max_peers,
+            });
+        }
+        Ok(())
+    }
+
+    fn check_bytes(&self, bytes: usize) -> Result<(), LimitError> {
+        if bytes > self.max_bytes {
+            return Err(LimitError::TooManyBytes {
+                bytes,
+                limit: self.max_bytes,
+            });
+        }
+        Ok(())
+    }
+
+    // Checks each insert's key and value size, returning their total size
+    fn check_inserts<K: ByteLen, V: ByteLen>(
+        &self,
+        inserts: &[Insert<K, V>],
+    ) -> Result<usize, LimitError> {
+        let mut bytes = 0;
+        for insert in inserts {
+            let key_size = insert.key.byte_len();
+            if key_size > self.max_key_size {
+                return Err(LimitError::KeyTooLarge {
+                    size: key_size,
+                    limit: self.max_key_size,
+                });
+            }
+            let value_size = insert.value.byte_len();
+            if value_size > self.max_value_size {
+                return Err(LimitError::ValueTooLarge {
+                    size: value_size,
+                    limit: self.max_value_size,
+                });
+            }
+            bytes += key_size + value_size;
+        }
+        Ok(bytes)
+    }
+}
+
+impl ByteLen for Vec<u8> {
+    fn byte_len(&self) -> usize {
+        self.len()
+    }
+}
+
+impl ByteLen for Box<[u8]> {
+    fn byte_len(&self) -> usize {
+        self.len()
+    }
+}
+
+impl<const N: usize> ByteLen for [u8; N] {
+    fn byte_len(&self) -> usize {
+        N
+    }
+}
+
+impl ByteLen for Bytes {
+    fn byte_len(&self) -> usize {
+        self.len()
+    }
+}
+
+impl ByteLen for String {
+    fn byte_len(&self) -> usize {
+        self.len()
+    }
+}
+
+macro_rules! impl_byte_len {
+    ($($t:ty),*) => {
+        $(impl ByteLen for $t {
+            fn byte_len(&self) -> usize {
+                size_of::<$t>()
+            }
+        })*
+    };
+}
+
+impl_byte_len!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
@@ -1,12 +1,34 @@
-use std::collections::{BTreeMap, BTreeSet, HashMap, btree_map};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, btree_map},
+    mem,
+    ops::Bound::{Excluded, Unbounded},
+    sync::{
+        OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
 
+use bytes::Bytes;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
 use roaring::RoaringTreemap;
 
+#[cfg(feature = "signing")]
+use crate::identity::{Identity, Signer};
 use crate::{
-    diff::{Diff, DiffPeerState, DiffRequest, DiffRequestPeerState, Insert},
+    access::{AccessControl, Rejected, RejectedOp},
+    diff::{
+        Diff, DiffPeerState, DiffPeerStateRef, DiffRef, DiffRequest, DiffRequestPeerState,
+        FilteredDiffRequest, Insert, InsertRef, RequestIndex, SubsetDiffRequest, TxnRange,
+    },
+    filter::KeyFilter,
     hlc::Hlc,
+    invariants::{Report, Violation},
+    limits::{ByteLen, LimitError, Limits},
     opset::OpSet,
     peer_id::PeerId,
+    reconcile::{self, HashRequest, HashResponse, Reconciler, RegionDiffRequest},
 };
 
 /// In-memory key value store backed by a roaring bitmap CRDT
@@ -15,15 +37,45 @@ pub struct MemStore<K, V> {
     entries: BTreeMap<K, Entry<V>>,
     peers: HashMap<PeerId, PeerState<K>>,
     opset: Option<OpSet<K, V>>,
+    max_drift: Duration,
+    limits: Limits,
+    access: Option<Box<dyn AccessControl<K> + Send + Sync>>,
+    subscriptions: Vec<(KeyFilter<K>, Subscription)>,
+    #[cfg(feature = "signing")]
+    signer: Option<Signer<K, V>>,
+}
+
+// Sync state of a key filter
+#[derive(Default)]
+struct Subscription {
+    // remote bookmarks covering only the filter's keys
+    bookmarks: HashMap<PeerId, Hlc>,
+    // HLCs of the entries received through the filter, which unsubscribing may evict
+    received: HashMap<PeerId, RoaringTreemap>,
 }
 
+/// Byte-oriented store whose diffs and opsets share reference-counted buffers with it.
+/// Received diffs can be decoded with [`Diff::decode`] to share the receive buffer too.
+pub type BytesMemStore = MemStore<Bytes, Bytes>;
+
+/// Default bound on how far a remote HLC may run ahead of the local clock
+pub const DEFAULT_MAX_DRIFT: Duration = Duration::from_secs(60);
+
 /// MemStore transactional context
 pub struct MemStoreTxn<'a, K, V> {
     store: &'a mut MemStore<K, V>,
     inserts: BTreeMap<K, V>,
     deletes: BTreeSet<K>,
+    savepoints: Vec<(Savepoint, BTreeMap<K, V>, BTreeSet<K>)>,
 }
 
+/// Savepoint within a MemStore transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Savepoint(u64);
+
+// Savepoint IDs are unique across transactions, so that foreign savepoints are rejected
+static NEXT_SAVEPOINT: AtomicU64 = AtomicU64::new(0);
+
 /// MemStore entries without local-specific metadata.
 /// Currently used for checking MemStore equality between peers.
 #[derive(Debug, PartialEq, Eq)]
@@ -34,12 +86,78 @@ struct Entry<V> {
     value: V,
     author: PeerId,
     hlc: Hlc,
+    // Relayed with the entry, even without the `signing` feature, at the cost of
+    // an empty `Option<Bytes>` per unsigned entry
+    signature: Option<Bytes>,
+}
+
+/// Entry metadata identifying the write that produced an entry's current value.
+/// Used for optimistic concurrency checks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryMeta {
+    author: PeerId,
+    hlc: Hlc,
+}
+
+/// Error returned when an entry's metadata does not match the expected metadata
+#[derive(Debug, thiserror::Error)]
+#[error("entry metadata conflict")]
+pub struct Conflict;
+
+/// Error returned when a savepoint has been released, or belongs to another transaction
+#[derive(Debug, thiserror::Error)]
+#[error("savepoint has been released")]
+pub struct ReleasedSavepoint;
+
+/// Error returned when a remote diff or opset violates a sync invariant.
+/// Peers are identified by their public ID and HLCs by their raw `u64` value.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum IntegrateError {
+    /// Multiple inserts share an author and HLC,
+    /// or an insert reuses an HLC already held by another key
+    #[error("duplicate HLC {hlc} of {}", peer.escape_ascii())]
+    DuplicateHlc { peer: Vec<u8>, hlc: u64 },
+    /// An insert at or below the local bookmark is no longer indexed,
+    /// so it has already been deleted or overwritten
+    #[error("insert {hlc} of {} at or below bookmark {bookmark} was already deleted", peer.escape_ascii())]
+    DeletedInsert {
+        peer: Vec<u8>,
+        hlc: u64,
+        bookmark: u64,
+    },
+    /// An HLC or bookmark is ahead of the local clock by more than the max drift.
+    /// HLCs of the local peer may not exceed the local bookmark.
+    #[error("HLC {hlc} of {} exceeds limit {limit}", peer.escape_ascii())]
+    FutureHlc { peer: Vec<u8>, hlc: u64, limit: u64 },
+    /// A transaction boundary ends before it starts
+    #[error("transaction {start}..={end} of {} is inverted", peer.escape_ascii())]
+    InvalidTxn { peer: Vec<u8>, start: u64, end: u64 },
+    /// The message exceeds a resource limit
+    #[error(transparent)]
+    Limit(#[from] LimitError),
+    /// An insert is unsigned, or not signed by its author
+    #[error("insert {hlc} of {} has an invalid signature", peer.escape_ascii())]
+    InvalidSignature { peer: Vec<u8>, hlc: u64 },
+    /// An opset is unsigned, or not signed by its sender
+    #[error("opset of {} has an invalid signature", peer.escape_ascii())]
+    InvalidOpsetSignature { peer: Vec<u8> },
 }
 
 struct PeerState<K> {
     index: RoaringTreemap,
     keys: HashMap<Hlc, K>,
     bookmark: Hlc,
+    txns: BTreeMap<Hlc, TxnRange>,
+    // serialized index for diff requests, reset whenever the index changes
+    request: OnceLock<Bytes>,
+}
+
+// HLCs of a single peer's entries carried by a diff
+struct DiffHlcs<'a, K> {
+    peer_id: &'a PeerId,
+    peer_state: &'a PeerState<K>,
+    inserts: RoaringTreemap,
+    deletes: RoaringTreemap,
 }
 
 impl<K> Default for PeerState<K> {
@@ -48,6 +166,8 @@ impl<K> Default for PeerState<K> {
             index: Default::default(),
             keys: Default::default(),
             bookmark: Default::default(),
+            txns: Default::default(),
+            request: Default::default(),
         }
     }
 }
@@ -63,9 +183,39 @@ impl<K: Clone + Ord, V: Clone> MemStore<K, V> {
             entries: BTreeMap::default(),
             peers,
             opset: None,
+            max_drift: DEFAULT_MAX_DRIFT,
+            limits: Limits::default(),
+            access: None,
+            subscriptions: Vec::new(),
+            #[cfg(feature = "signing")]
+            signer: None,
         }
     }
 
+    /// Sets how far remote HLCs may run ahead of the local clock
+    /// before [`MemStore::try_integrate_diff`] and [`MemStore::try_integrate_opset`] reject them
+    pub fn with_max_drift(mut self, max_drift: Duration) -> Self {
+        self.max_drift = max_drift;
+        self
+    }
+
+    /// Sets the resource limits enforced on incoming sync traffic
+    /// by [`MemStore::try_build_diff`] and the `try_integrate_*` methods
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sets the access control policy consulted by [`MemStore::build_diff_for`]
+    /// and the `integrate_*_from` methods
+    pub fn with_access_control<A>(mut self, access: A) -> Self
+    where
+        A: AccessControl<K> + Send + Sync + 'static,
+    {
+        self.access = Some(Box::new(access));
+        self
+    }
+
     /// Begins tracking opsets
     pub fn with_opset(mut self) -> Self {
         if self.opset.is_none() {
@@ -76,11 +226,12 @@ impl<K: Clone + Ord, V: Clone> MemStore<K, V> {
 
     /// Takes the current opset and begins a new one
     pub fn take_opset(&mut self) -> OpSet<K, V> {
-        let old = self
+        let mut old = self
             .opset
             .take()
             .unwrap_or_else(|| OpSet::new(self.local_id.clone()));
         self.opset = Some(OpSet::new(self.local_id.clone()));
+        self.sign_opset(&mut old);
         old
     }
 
@@ -111,6 +262,7 @@ impl<K: Clone + Ord, V: Clone> MemStore<K, V> {
             store: self,
             inserts: BTreeMap::default(),
             deletes: BTreeSet::default(),
+            savepoints: Vec::default(),
         }
     }
 
@@ -131,9 +283,9 @@ impl<K: Clone + Ord, V: Clone> MemStore<K, V> {
         } else {
             peer_state.bookmark.next()
         };
-        peer_state.index.insert(hlc.to_u64());
-        peer_state.keys.insert(hlc, key.clone());
+        peer_state.insert(hlc, key.clone());
         peer_state.bookmark = hlc;
+        let signature = self.sign_insert(&key, &value, hlc);
 
         // add insert to opset
         if let Some(opset) = &mut self.opset {
@@ -141,6 +293,7 @@ impl<K: Clone + Ord, V: Clone> MemStore<K, V> {
                 key: key.clone(),
                 value: value.clone(),
                 hlc,
+                signature: signature.clone(),
             });
         }
 
@@ -149,19 +302,16 @@ impl<K: Clone + Ord, V: Clone> MemStore<K, V> {
             value,
             author: self.local_id.clone(),
             hlc,
+            signature,
         };
 
         let old_entry = self.entries.insert(key, entry)?;
 
         // update peer state for overwritten entry
-        let peer_state = self
-            .peers
+        self.peers
             .get_mut(&old_entry.author)
-            .expect("invalid peer state accounting");
-        peer_state.index.remove(old_entry.hlc.to_u64());
-        if old_entry.author != self.local_id {
-            peer_state.keys.remove(&old_entry.hlc);
-        }
+            .expect("invalid peer state accounting")
+            .remove(old_entry.hlc);
 
         // add delete to opset
         if let Some(opset) = &mut self.opset {
@@ -171,12 +321,57 @@ impl<K: Clone + Ord, V: Clone> MemStore<K, V> {
         Some(old_entry.value)
     }
 
+    // Private commit method
+    // - all inserts share a contiguous HLC range that starts after the current bookmark
+    // - the range is recorded as a transaction boundary if it spans multiple inserts,
+    //   or if the transaction also deleted entries
+    fn commit_private(&mut self, inserts: BTreeMap<K, V>, deletes: BTreeSet<K>) {
+        let start = self.mut_local_peer_state().bookmark.next();
+        let mut hlc = start;
+        for (key, value) in inserts {
+            self.insert_private(key, value, Some(hlc));
+            hlc = hlc.inc();
+        }
+        let mut deleted = false;
+        for key in deletes {
+            deleted |= self.remove_private(&key).is_some();
+        }
+        if hlc > start.inc() || (hlc > start && deleted) {
+            let txn = TxnRange {
+                start,
+                end: Hlc::from_u64(hlc.to_u64() - 1),
+                deletes: deleted,
+            };
+            self.mut_local_peer_state().add_txn(txn);
+            if let Some(opset) = &mut self.opset {
+                opset.add_txn(txn);
+            }
+        }
+    }
+
     fn mut_local_peer_state(&mut self) -> &mut PeerState<K> {
         self.peers
             .get_mut(&self.local_id)
             .expect("local peer state must always exist")
     }
 
+    // Signs a local insert, if the store has an identity
+    fn sign_insert(&self, _key: &K, _value: &V, _hlc: Hlc) -> Option<Bytes> {
+        #[cfg(feature = "signing")]
+        if let Some(signer) = &self.signer {
+            return Some(signer.sign_insert(_key, _value, _hlc));
+        }
+        None
+    }
+
+    // Signs a local opset, if the store has an identity
+    fn sign_opset(&self, _opset: &mut OpSet<K, V>) {
+        #[cfg(feature = "signing")]
+        if let Some(signer) = &self.signer {
+            signer.sign_opset(_opset);
+        }
+    }
+
     /// Removes a key from the CRDT, returning the value at the key if the key was previously in the CRDT.
     pub fn remove(&mut self, key: &K) -> Option<V> {
         self.remove_private(key)
@@ -185,13 +380,10 @@ impl<K: Clone + Ord, V: Clone> MemStore<K, V> {
     fn remove_private(&mut self, key: &K) -> Option<V> {
         let old_entry = self.entries.remove(key)?;
 
-        let peer_state = self
-            .peers
+        self.peers
             .get_mut(&old_entry.author)
-            .expect("invalid peer state accounting");
-
-        peer_state.index.remove(old_entry.hlc.to_u64());
-        peer_state.keys.remove(&old_entry.hlc);
+            .expect("invalid peer state accounting")
+            .remove(old_entry.hlc);
 
         if let Some(opset) = &mut self.opset {
             opset.add_delete(old_entry.author, old_entry.hlc);
@@ -205,6 +397,122 @@ impl<K: Clone + Ord, V: Clone> MemStore<K, V> {
         self.entries.get(key).map(|entry| &entry.value)
     }
 
+    /// Returns the metadata of the entry at the key
+    pub fn get_meta(&self, key: &K) -> Option<EntryMeta> {
+        self.entries.get(key).map(|entry| EntryMeta {
+            author: entry.author.clone(),
+            hlc: entry.hlc,
+        })
+    }
+
+    /// Inserts a key-value pair iff the entry's metadata matches `expected`,
+    /// where `None` expects the key to be absent
+    pub fn compare_and_swap(
+        &mut self,
+        key: K,
+        expected: Option<&EntryMeta>,
+        value: V,
+    ) -> Result<Option<V>, Conflict> {
+        if self.get_meta(&key).as_ref() != expected {
+            return Err(Conflict);
+        }
+        Ok(self.insert(key, value))
+    }
+
+    /// Inserts a key-value pair iff the key is absent
+    pub fn insert_if_absent(&mut self, key: K, value: V) -> Result<(), Conflict> {
+        self.compare_and_swap(key, None, value).map(|_| ())
+    }
+
+    /// Checks the store's peer state accounting, returning a report of every violation:
+    /// - every entry's HLC appears in its author's index and keys
+    /// - every indexed HLC and key has an entry
+    /// - the local bookmark bounds all local HLCs
+    /// - every transaction boundary has remaining inserts
+    ///
+    /// Remote bookmarks are not checked, since opsets add inserts without advancing them.
+    /// The check is O(n), so it is meant for tests, debug builds and diagnostics.
+    pub fn check_invariants(&self) -> Report {
+        let mut report = Report::default();
+
+        for (key, entry) in &self.entries {
+            let peer = entry.author.as_slice().to_vec();
+            let hlc = entry.hlc.to_u64();
+            let Some(state) = self.peers.get(&entry.author) else {
+                report.push(Violation::UnknownAuthor { peer, hlc });
+                continue;
+            };
+            if !state.index.contains(hlc) {
+                report.push(Violation::MissingIndex {
+                    peer: peer.clone(),
+                    hlc,
+                });
+            }
+            if state.keys.get(&entry.hlc) != Some(key) {
+                report.push(Violation::MissingKey { peer, hlc });
+            }
+        }
+
+        for (peer_id, state) in &self.peers {
+            let peer = peer_id.as_slice();
+            let has_entry = |hlc: Hlc| {
+                state
+                    .keys
+                    .get(&hlc)
+                    .and_then(|key| self.entries.get(key))
+                    .is_some_and(|entry| entry.author == *peer_id && entry.hlc == hlc)
+            };
+
+            for hlc in &state.index {
+                if !has_entry(Hlc::from_u64(hlc)) {
+                    report.push(Violation::OrphanedIndex {
+                        peer: peer.to_vec(),
+                        hlc,
+                    });
+                }
+            }
+            for hlc in state.keys.keys() {
+                if !state.index.contains(hlc.to_u64()) && !has_entry(*hlc) {
+                    report.push(Violation::OrphanedKey {
+                        peer: peer.to_vec(),
+                        hlc: hlc.to_u64(),
+                    });
+                }
+            }
+
+            if *peer_id == self.local_id
+                && let Some(max) = state.index.max()
+                && max > state.bookmark.to_u64()
+            {
+                report.push(Violation::AboveBookmark {
+                    peer: peer.to_vec(),
+                    hlc: max,
+                    bookmark: state.bookmark.to_u64(),
+                });
+            }
+
+            for txn in state.txns.values() {
+                if state.txn_len(*txn) == 0 {
+                    report.push(Violation::EmptyTxn {
+                        peer: peer.to_vec(),
+                        start: txn.start.to_u64(),
+                        end: txn.end.to_u64(),
+                    });
+                }
+            }
+
+            if let Some(bytes) = state.request.get()
+                && RoaringTreemap::deserialize_from(&bytes[..]).ok().as_ref() != Some(&state.index)
+            {
+                report.push(Violation::StaleRequest {
+                    peer: peer.to_vec(),
+                });
+            }
+        }
+
+        report
+    }
+
     /// Returns a diff request object based on the current local state
     pub fn request_diff(&self) -> DiffRequest {
         DiffRequest(
@@ -215,183 +523,1140 @@ impl<K: Clone + Ord, V: Clone> MemStore<K, V> {
         )
     }
 
-    /// Builds a diff from the request object
+    /// Builds a diff from the request object.
+    /// Requested peers that are unknown locally are ignored.
     pub fn build_diff(&self, request: DiffRequest) -> Diff<K, V> {
-        let mut diff_peer_states = HashMap::with_capacity(self.peers.len());
+        let hlcs = self.diff_hlcs(&request, None, false);
+        self.materialize_diff(hlcs, |_| true, false)
+    }
 
-        for (peer_id, peer_state) in &self.peers {
-            let mut diff_peer_state = DiffPeerState {
-                inserts: Vec::default(),
-                deletes: RoaringTreemap::new(),
-                bookmark: peer_state.bookmark,
-            };
+    /// Builds a diff from the request object, borrowing its entries from the store
+    /// so that it can be serialized without cloning them
+    pub fn build_diff_ref(&self, request: DiffRequest) -> DiffRef<'_, K, V> {
+        let hlcs = self.diff_hlcs(&request, None, false);
+        self.borrow_diff(hlcs, |_| true, false)
+    }
+
+    /// Builds a diff from a peer's request object, leaving out the entries
+    /// the access control policy does not let the peer read.
+    /// The peer's bookmarks are held below the left out entries, so they are sent
+    /// once the peer is granted access, at the cost of checking them again on each sync.
+    pub fn build_diff_for(&self, peer: &str, request: DiffRequest) -> Diff<K, V> {
+        let hlcs = self.diff_hlcs(&request, None, false);
+        let reader = PeerId::from_str(peer);
+        self.materialize_diff(hlcs, |key| self.can_read(&reader, key), true)
+    }
+
+    /// Returns a diff request object covering the listed peers only.
+    /// Peers unknown locally are requested from scratch.
+    pub fn request_subset_diff(&self, peers: &[&str]) -> SubsetDiffRequest {
+        let request = peers
+            .iter()
+            .map(|peer| {
+                let peer_id = PeerId::from_str(peer);
+                let state = self.peers.get(&peer_id).map_or_else(
+                    || DiffRequestPeerState {
+                        index: RequestIndex::Bitmap(RoaringTreemap::new()),
+                        bookmark: Hlc::default(),
+                    },
+                    PeerState::diff_request,
+                );
+                (peer_id, state)
+            })
+            .collect();
+        SubsetDiffRequest(DiffRequest(request))
+    }
+
+    /// Builds a diff from a subset request object, covering the requested peers only
+    pub fn build_subset_diff(&self, request: SubsetDiffRequest) -> Diff<K, V> {
+        let hlcs = self.diff_hlcs(&request.0, None, true);
+        self.materialize_diff(hlcs, |_| true, false)
+    }
 
-            if let Some(request) = request.0.get(peer_id) {
-                // inserts: all e ⊂ (local - remote) AND e > remote.max
-                let mut insert_hlcs = &peer_state.index - &request.index;
-                insert_hlcs.remove_range(0..=request.bookmark.to_u64());
-                diff_peer_state.inserts = insert_hlcs
+    /// Returns a diff request object for the keys matching the filter.
+    /// The request's index only covers local entries matching the filter, and its bookmarks
+    /// include the filter's sync state.
+    pub fn request_filtered_diff(&self, filter: &KeyFilter<K>) -> FilteredDiffRequest<K> {
+        let subscription = self.subscription(filter);
+        let request = self
+            .peers
+            .iter()
+            .map(|(peer_id, state)| {
+                let index = state
+                    .keys
                     .iter()
-                    .map(|hlc| -> _ {
-                        let hlc = Hlc::from_u64(hlc);
-                        let key = peer_state.keys.get(&hlc).expect("missing key for HLC");
-                        let value = self.get(key).expect("missing value for key");
-                        Insert {
-                            key: key.to_owned(),
-                            value: value.to_owned(),
-                            hlc,
-                        }
-                    })
+                    .filter(|(hlc, key)| state.index.contains(hlc.to_u64()) && filter.contains(key))
+                    .map(|(hlc, _)| hlc.to_u64())
                     .collect();
+                let bookmark = subscription
+                    .and_then(|bookmarks| bookmarks.get(peer_id))
+                    .map_or(state.bookmark, |bookmark| state.bookmark.max(*bookmark));
+                let state = DiffRequestPeerState {
+                    index: RequestIndex::Bitmap(index),
+                    bookmark,
+                };
+                (peer_id.to_owned(), state)
+            })
+            .collect();
+        FilteredDiffRequest {
+            filter: filter.clone(),
+            request: DiffRequest(request),
+        }
+    }
+
+    /// Builds a diff of the keys matching a filtered request's filter.
+    /// Deletes are bounded by the filter's sync state, so a partial replica can push its
+    /// deletes, while bookmarks are always the full bookmarks.
+    pub fn build_filtered_diff(&self, request: FilteredDiffRequest<K>) -> Diff<K, V> {
+        let hlcs = self.diff_hlcs(&request.request, self.subscription(&request.filter), false);
+        self.materialize_diff(hlcs, |key| request.filter.contains(key), false)
+    }
 
-                // deletes: all e ⊂ (remote - local) AND e ≤ local.max
-                diff_peer_state.deletes = &request.index - &peer_state.index;
-                diff_peer_state
-                    .deletes
-                    .remove_range(diff_peer_state.bookmark.to_u64()..);
-            } else {
-                // inserts: all e ⊂ local
-                diff_peer_state.inserts = peer_state
-                    .index
+    /// Integrates a diff built from [`MemStore::request_filtered_diff`] with the same filter.
+    /// The remote bookmarks only cover the filter's keys, so they are recorded as
+    /// the filter's sync state rather than as full bookmarks.
+    pub fn integrate_filtered_diff(&mut self, filter: &KeyFilter<K>, mut diff: Diff<K, V>) {
+        let received: Vec<_> = diff
+            .0
+            .iter_mut()
+            .map(|(peer_id, diff_peer)| {
+                let hlcs: RoaringTreemap = diff_peer
+                    .inserts
                     .iter()
-                    .map(|hlc| {
-                        let hlc = Hlc::from_u64(hlc);
-                        let key = peer_state.keys.get(&hlc).expect("missing key for HLC");
-                        let value = self.get(key).expect("missing value for key");
-                        Insert {
-                            key: key.to_owned(),
-                            value: value.to_owned(),
-                            hlc,
-                        }
-                    })
+                    .map(|insert| insert.hlc.to_u64())
                     .collect();
+                (peer_id.clone(), mem::take(&mut diff_peer.bookmark), hlcs)
+            })
+            .collect();
+        self.integrate_diff(diff);
+
+        let subscription = match self.subscriptions.iter().position(|(f, _)| f == filter) {
+            Some(i) => &mut self.subscriptions[i].1,
+            None => {
+                self.subscriptions
+                    .push((filter.clone(), Subscription::default()));
+                &mut self.subscriptions.last_mut().expect("just pushed").1
+            }
+        };
+        for (peer_id, bookmark, hlcs) in received {
+            // only keep the HLCs that were integrated and are still indexed
+            if let Some(peer) = self.peers.get(&peer_id) {
+                let received = subscription.received.entry(peer_id.clone()).or_default();
+                *received |= hlcs;
+                *received &= &peer.index;
             }
+            let entry = subscription.bookmarks.entry(peer_id).or_default();
+            *entry = (*entry).max(bookmark);
+        }
+    }
+
+    /// Drops a filter's sync state, evicting the remote entries that arrived through it
+    /// and are not covered by another subscription. Filters that are not subscribed
+    /// are ignored. Evicted entries are dropped locally rather than deleted, so they are
+    /// not synced as deletes.
+    pub fn unsubscribe(&mut self, filter: &KeyFilter<K>) {
+        let Some(i) = self.subscriptions.iter().position(|(f, _)| f == filter) else {
+            return;
+        };
+        let (filter, subscription) = self.subscriptions.remove(i);
+
+        // entries at or below their author's full bookmark are covered by full syncs,
+        // and entries that arrived otherwise, e.g. by opset, are kept
+        let evicted: Vec<K> = self
+            .entries
+            .iter()
+            .filter(|(key, entry)| {
+                entry.author != self.local_id
+                    && entry.hlc > self.peers[&entry.author].bookmark
+                    && subscription
+                        .received
+                        .get(&entry.author)
+                        .is_some_and(|hlcs| hlcs.contains(entry.hlc.to_u64()))
+                    && filter.contains(key)
+                    && !self.subscriptions.iter().any(|(f, _)| f.contains(key))
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in evicted {
+            let entry = self.entries.remove(&key).expect("evicted entry exists");
+            self.peers
+                .get_mut(&entry.author)
+                .expect("invalid peer state accounting")
+                .remove(entry.hlc);
+        }
+    }
+
+    /// Begins a hash-based reconciliation with a remote store whose state mostly matches.
+    /// See [`crate::reconcile`] for the protocol.
+    pub fn reconciler(&self) -> Reconciler<'_> {
+        Reconciler::new(
+            self.peers
+                .iter()
+                .map(|(peer_id, state)| (peer_id, &state.index, state.bookmark)),
+        )
+    }
 
-            if !diff_peer_state.inserts.is_empty() || diff_peer_state.deletes.is_empty() {
-                diff_peer_states.insert(peer_id.clone(), diff_peer_state);
+    /// Lists the ranges of a reconciliation round whose hashes differ from the local state
+    pub fn compare_hashes(&self, request: &HashRequest) -> HashResponse {
+        reconcile::compare_hashes(request, |peer_id| {
+            self.peers.get(peer_id).map(|state| &state.index)
+        })
+    }
+
+    /// Builds a diff from a reconciliation's region request,
+    /// following [`MemStore::build_diff`] within the differing ranges only
+    pub fn build_region_diff(&self, request: RegionDiffRequest) -> Diff<K, V> {
+        let mut diff_hlcs = self.diff_hlcs(&request.request, None, false);
+        for hlcs in &mut diff_hlcs {
+            if request.request.0.contains_key(hlcs.peer_id) {
+                let regions = request
+                    .regions
+                    .get(hlcs.peer_id)
+                    .map_or(&[][..], Vec::as_slice);
+                hlcs.inserts = reconcile::restrict(&hlcs.inserts, regions);
+                hlcs.deletes = reconcile::restrict(&hlcs.deletes, regions);
             }
         }
+        self.materialize_diff(diff_hlcs, |_| true, false)
+    }
+
+    fn subscription(&self, filter: &KeyFilter<K>) -> Option<&HashMap<PeerId, Hlc>> {
+        self.subscriptions
+            .iter()
+            .find(|(f, _)| f == filter)
+            .map(|(_, subscription)| &subscription.bookmarks)
+    }
+
+    // Computes the HLCs a diff carries for each peer, skipping peers whose state
+    // the remote already has, and peers left out of a subset request
+    fn diff_hlcs<'a>(
+        &'a self,
+        request: &DiffRequest,
+        subscription: Option<&HashMap<PeerId, Hlc>>,
+        subset: bool,
+    ) -> Vec<DiffHlcs<'a, K>> {
+        self.peers
+            .iter()
+            .filter(|(peer_id, _)| !subset || request.0.contains_key(peer_id))
+            .filter_map(|(peer_id, peer_state)| {
+                DiffHlcs::new(peer_id, peer_state, request, subscription)
+            })
+            .collect()
+    }
+
+    // Clones the entries for each peer's diff HLCs whose keys are kept
+    fn materialize_diff(
+        &self,
+        diff_hlcs: Vec<DiffHlcs<'_, K>>,
+        keep: impl Fn(&K) -> bool,
+        hold: bool,
+    ) -> Diff<K, V> {
+        self.borrow_diff(diff_hlcs, keep, hold).into_owned()
+    }
+
+    // Borrows the entries for each peer's diff HLCs whose keys are kept.
+    // If `hold` is set, each peer's bookmark is held below its first left out insert.
+    fn borrow_diff<'a>(
+        &'a self,
+        diff_hlcs: Vec<DiffHlcs<'a, K>>,
+        keep: impl Fn(&K) -> bool,
+        hold: bool,
+    ) -> DiffRef<'a, K, V> {
+        DiffRef(
+            diff_hlcs
+                .into_iter()
+                .map(|hlcs| {
+                    let mut withheld = None;
+                    let inserts = hlcs
+                        .inserts
+                        .iter()
+                        .filter_map(|hlc| self.indexed_insert(hlcs.peer_state, hlc))
+                        .filter(|insert| {
+                            let kept = keep(insert.key);
+                            if !kept && withheld.is_none() {
+                                withheld = Some(insert.hlc);
+                            }
+                            kept
+                        })
+                        .collect();
+                    let peer_id = hlcs.peer_id;
+                    let mut state = hlcs.into_state(inserts);
+                    if let Some(hlc) = withheld.filter(|_| hold) {
+                        let below = Hlc::from_u64(hlc.to_u64().saturating_sub(1));
+                        state.bookmark = state.bookmark.min(below);
+                    }
+                    (peer_id, state)
+                })
+                .collect(),
+        )
+    }
+
+    fn can_read(&self, reader: &PeerId, key: &K) -> bool {
+        self.access
+            .as_ref()
+            .is_none_or(|access| access.can_read(reader.as_slice(), key))
+    }
 
-        Diff(diff_peer_states)
+    // Looks up the insert for an indexed HLC.
+    // HLCs without a key or entry are skipped rather than trusted.
+    fn indexed_insert<'a>(
+        &'a self,
+        peer_state: &'a PeerState<K>,
+        hlc: u64,
+    ) -> Option<InsertRef<'a, K, V>> {
+        let hlc = Hlc::from_u64(hlc);
+        let key = peer_state.keys.get(&hlc)?;
+        let entry = self.entries.get(key)?;
+        Some(InsertRef {
+            key,
+            value: &entry.value,
+            hlc,
+            signature: entry.signature.as_ref(),
+        })
     }
 
-    /// Integrates a diff into the local CRDT
-    pub fn integrate_diff(&mut self, diff: Diff<K, V>) {
-        let mut overwritten: HashMap<PeerId, Vec<Hlc>> = HashMap::default();
+    /// Integrates a diff into the local CRDT.
+    /// Inserts and bookmarks claimed for the local peer are ignored, so a remote
+    /// cannot advance the local clock; deletes of local entries still apply.
+    /// If the store has an identity, inserts not signed by their author are dropped
+    /// like inserts rejected by access control.
+    /// Each transaction in the diff becomes visible all at once; use [`Diff::split`]
+    /// to chunk large diffs without splitting transactions.
+    pub fn integrate_diff(&mut self, mut diff: Diff<K, V>) {
+        self.verify_diff(&mut diff);
+        self.integrate_verified_diff(diff);
+    }
 
+    fn integrate_verified_diff(&mut self, diff: Diff<K, V>) {
         // integrate deletes
         for (peer_id, diff_peer) in &diff.0 {
-            if let Some(peer) = self.peers.get_mut(peer_id) {
-                peer.index -= &diff_peer.deletes;
-                for delete in &diff_peer.deletes {
-                    if let Some(key) = peer.keys.remove(&Hlc::from_u64(delete)) {
-                        self.entries.remove(&key);
-                    }
-                }
-            }
+            self.integrate_deletes(peer_id, &diff_peer.deletes);
         }
 
-        // integrate inserts
+        // integrate inserts, ignoring remote claims about the local peer's own writes
         for (peer_id, diff_peer) in diff.0 {
-            self.integrate_peer_inserts(peer_id, diff_peer, &mut overwritten);
+            if peer_id != self.local_id {
+                self.integrate_peer_inserts(peer_id, diff_peer);
+            }
         }
     }
 
-    fn integrate_peer_inserts(
-        &mut self,
-        peer_id: PeerId,
-        diff_peer: DiffPeerState<K, V>,
-        overwritten: &mut HashMap<PeerId, Vec<Hlc>>,
-    ) -> Hlc {
-        let peer = self.peers.entry(peer_id.to_owned()).or_default();
+    /// Integrates a diff received from a peer, leaving out and returning the ops
+    /// the access control policy rejects.
+    /// A rejected insert also rejects the rest of its transaction, and its author's bookmark
+    /// is held below it so that a permitted peer can still send it later.
+    pub fn integrate_diff_from(&mut self, peer: &str, mut diff: Diff<K, V>) -> Rejected<K> {
+        self.verify_diff(&mut diff);
+        self.integrate_verified_diff_from(peer, diff)
+    }
+
+    fn integrate_verified_diff_from(&mut self, peer: &str, mut diff: Diff<K, V>) -> Rejected<K> {
+        let rejected = self.authorize_diff(&PeerId::from_str(peer), &mut diff);
+        self.integrate_verified_diff(diff);
+        rejected
+    }
 
+    fn integrate_peer_inserts(&mut self, peer_id: PeerId, diff_peer: DiffPeerState<K, V>) {
+        self.peers.entry(peer_id.clone()).or_default();
         for insert in diff_peer.inserts {
-            let did_insert = match self.entries.entry(insert.key.clone()) {
-                btree_map::Entry::Vacant(entry) => {
-                    entry.insert(Entry {
+            self.integrate_insert(&peer_id, insert);
+        }
+
+        let peer = self
+            .peers
+            .get_mut(&peer_id)
+            .expect("invalid peer state accounting");
+        for txn in diff_peer.txns {
+            peer.add_txn(txn);
+        }
+        peer.bookmark = peer.bookmark.max(diff_peer.bookmark);
+    }
+
+    /// Integrates an opset into the local CRDT.
+    /// Deletes are applied after inserts, since an opset may delete its own inserts.
+    /// An opset claiming to be authored by the local peer only has its deletes applied.
+    /// If the store has an identity, an opset not signed by its sender, or carrying
+    /// inserts not signed by their author, is ignored.
+    pub fn integrate_opset(&mut self, opset: OpSet<K, V>) {
+        if self.opset_verified(&opset) {
+            self.integrate_verified_opset(opset);
+        }
+    }
+
+    fn integrate_verified_opset(&mut self, opset: OpSet<K, V>) {
+        // integrate inserts
+        if opset.peer_id != self.local_id {
+            self.peers.entry(opset.peer_id.clone()).or_default();
+            for insert in opset.inserts {
+                self.integrate_insert(&opset.peer_id, insert);
+            }
+
+            let peer = self
+                .peers
+                .get_mut(&opset.peer_id)
+                .expect("invalid peer state accounting");
+            for txn in opset.txns {
+                peer.add_txn(txn);
+            }
+        }
+
+        // integrate deletes
+        for (peer_id, deletes) in &opset.deletes {
+            self.integrate_deletes(peer_id, deletes);
+        }
+    }
+
+    /// Integrates an opset received from a peer, leaving out and returning the ops
+    /// the access control policy rejects.
+    /// A rejected insert also rejects the rest of its transaction.
+    pub fn integrate_opset_from(&mut self, peer: &str, opset: OpSet<K, V>) -> Rejected<K> {
+        if !self.opset_verified(&opset) {
+            return Rejected::default();
+        }
+        self.integrate_verified_opset_from(peer, opset)
+    }
+
+    fn integrate_verified_opset_from(&mut self, peer: &str, mut opset: OpSet<K, V>) -> Rejected<K> {
+        let rejected = self.authorize_opset(&PeerId::from_str(peer), &mut opset);
+        self.integrate_verified_opset(opset);
+        rejected
+    }
+
+    // Verifies an insert's signature, if the store has an identity
+    fn verify_insert(
+        &self,
+        _peer_id: &PeerId,
+        _insert: &Insert<K, V>,
+    ) -> Result<(), IntegrateError> {
+        #[cfg(feature = "signing")]
+        if let Some(signer) = &self.signer
+            && !signer.verify_insert(_peer_id, _insert)
+        {
+            return Err(IntegrateError::InvalidSignature {
+                peer: _peer_id.as_slice().to_vec(),
+                hlc: _insert.hlc.to_u64(),
+            });
+        }
+        Ok(())
+    }
+
+    // Verifies an opset's signature, if the store has an identity
+    fn verify_opset(&self, _opset: &OpSet<K, V>) -> Result<(), IntegrateError> {
+        #[cfg(feature = "signing")]
+        if let Some(signer) = &self.signer
+            && !signer.verify_opset(_opset)
+        {
+            return Err(IntegrateError::InvalidOpsetSignature {
+                peer: _opset.peer_id.as_slice().to_vec(),
+            });
+        }
+        Ok(())
+    }
+
+    // Returns `true` if the opset and its inserts are signed, if the store has an identity
+    fn opset_verified(&self, opset: &OpSet<K, V>) -> bool {
+        self.verify_opset(opset).is_ok()
+            && opset
+                .inserts
+                .iter()
+                .all(|insert| self.verify_insert(&opset.peer_id, insert).is_ok())
+    }
+
+    // Removes the inserts not signed by their author from a diff, if the store has
+    // an identity, along with the rest of their transactions
+    fn verify_diff(&self, _diff: &mut Diff<K, V>) {
+        #[cfg(feature = "signing")]
+        if let Some(signer) = &self.signer {
+            for (peer_id, diff_peer) in &mut _diff.0 {
+                let lowest = reject_inserts(
+                    &mut diff_peer.inserts,
+                    &diff_peer.txns,
+                    |insert| !signer.verify_insert(peer_id, insert),
+                    |_| {},
+                );
+                diff_peer.hold_bookmark(lowest);
+            }
+        }
+    }
+
+    // Removes the ops the sender may not apply from a diff
+    fn authorize_diff(&self, sender: &PeerId, diff: &mut Diff<K, V>) -> Rejected<K> {
+        let mut rejected = Rejected::default();
+        let Some(access) = &self.access else {
+            return rejected;
+        };
+        for (peer_id, diff_peer) in &mut diff.0 {
+            self.authorize_deletes(
+                access.as_ref(),
+                sender,
+                peer_id,
+                &mut diff_peer.deletes,
+                &mut rejected,
+            );
+            let lowest = authorize_inserts(
+                access.as_ref(),
+                sender,
+                peer_id,
+                &mut diff_peer.inserts,
+                &diff_peer.txns,
+                &mut rejected,
+            );
+            diff_peer.hold_bookmark(lowest);
+        }
+        rejected
+    }
+
+    // Removes the ops the sender may not apply from an opset
+    fn authorize_opset(&self, sender: &PeerId, opset: &mut OpSet<K, V>) -> Rejected<K> {
+        let mut rejected = Rejected::default();
+        let Some(access) = &self.access else {
+            return rejected;
+        };
+        for (peer_id, deletes) in &mut opset.deletes {
+            self.authorize_deletes(access.as_ref(), sender, peer_id, deletes, &mut rejected);
+        }
+        authorize_inserts(
+            access.as_ref(),
+            sender,
+            &opset.peer_id,
+            &mut opset.inserts,
+            &opset.txns,
+            &mut rejected,
+        );
+        rejected
+    }
+
+    // Removes the deletes of locally indexed entries whose keys the sender may not delete.
+    // Deletes of unknown HLCs have no effect, so they are kept.
+    fn authorize_deletes(
+        &self,
+        access: &(dyn AccessControl<K> + Send + Sync),
+        sender: &PeerId,
+        peer_id: &PeerId,
+        deletes: &mut RoaringTreemap,
+        rejected: &mut Rejected<K>,
+    ) {
+        let Some(peer) = self.peers.get(peer_id) else {
+            return;
+        };
+        for hlc in &(&*deletes & &peer.index) {
+            if let Some(key) = peer.keys.get(&Hlc::from_u64(hlc))
+                && !access.can_delete(sender.as_slice(), key)
+            {
+                deletes.remove(hlc);
+                rejected.deletes.push(RejectedOp {
+                    peer: peer_id.as_slice().to_vec(),
+                    hlc,
+                    key: key.clone(),
+                });
+            }
+        }
+    }
+
+    // Integrates remote deletes of a peer's entries.
+    // Only locally indexed HLCs are visited, so the cost is bounded by the local index
+    // no matter how large the remote bitmap is.
+    fn integrate_deletes(&mut self, peer_id: &PeerId, deletes: &RoaringTreemap) {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            for delete in &(deletes & &peer.index) {
+                if let Some(key) = peer.remove(Hlc::from_u64(delete)) {
+                    self.entries.remove(&key);
+                }
+            }
+        }
+    }
+
+    // Integrates a single remote insert, returning `true` if the insert was applied.
+    // An overwritten entry is removed from its author's peer state.
+    // An insert reusing an HLC its author already holds for another key is ignored.
+    fn integrate_insert(&mut self, peer_id: &PeerId, insert: Insert<K, V>) -> bool {
+        if self
+            .peers
+            .get(peer_id)
+            .and_then(|peer| peer.keys.get(&insert.hlc))
+            .is_some_and(|key| *key != insert.key)
+        {
+            return false;
+        }
+
+        let old = match self.entries.entry(insert.key.clone()) {
+            btree_map::Entry::Vacant(entry) => {
+                entry.insert(Entry {
+                    value: insert.value,
+                    author: peer_id.clone(),
+                    hlc: insert.hlc,
+                    signature: insert.signature,
+                });
+                None
+            }
+            btree_map::Entry::Occupied(mut entry) => {
+                // replace the old entry iff the new insert follows causally
+                if entry.get().overwritten_by(peer_id, insert.hlc) {
+                    Some(entry.insert(Entry {
                         value: insert.value,
                         author: peer_id.clone(),
                         hlc: insert.hlc,
-                    });
-                    true
-                }
-                btree_map::Entry::Occupied(mut entry) => {
-                    // replace the old entry iff the new insert follows causally
-                    let old = entry.get_mut();
-                    let id = peer_id.clone();
-                    if old.hlc < insert.hlc || old.hlc == insert.hlc && old.author < id {
-                        let old = entry.insert(Entry {
-                            value: insert.value,
-                            author: id,
-                            hlc: insert.hlc,
-                        });
-                        overwritten.entry(old.author).or_default().push(old.hlc);
-                        true
-                    } else {
-                        false
-                    }
+                        signature: insert.signature,
+                    }))
+                } else {
+                    return false;
                 }
-            };
+            }
+        };
+
+        if let Some(old) = old {
+            self.peers
+                .get_mut(&old.author)
+                .expect("invalid peer state accounting")
+                .remove(old.hlc);
+        }
+
+        let peer = self
+            .peers
+            .get_mut(peer_id)
+            .expect("invalid peer state accounting");
+        peer.insert(insert.hlc, insert.key);
+        true
+    }
+}
+
+// Removes the inserts whose keys the sender may not insert, along with the rest of their
+// transactions, returning the lowest rejected HLC
+fn authorize_inserts<K: Clone, V>(
+    access: &(dyn AccessControl<K> + Send + Sync),
+    sender: &PeerId,
+    peer_id: &PeerId,
+    inserts: &mut Vec<Insert<K, V>>,
+    txns: &[TxnRange],
+    rejected: &mut Rejected<K>,
+) -> Option<Hlc> {
+    reject_inserts(
+        inserts,
+        txns,
+        |insert| !access.can_insert(sender.as_slice(), &insert.key),
+        |insert| {
+            rejected.inserts.push(RejectedOp {
+                peer: peer_id.as_slice().to_vec(),
+                hlc: insert.hlc.to_u64(),
+                key: insert.key,
+            })
+        },
+    )
+}
 
-            if did_insert {
-                peer.index.insert(insert.hlc.to_u64());
-                peer.keys.insert(insert.hlc, insert.key);
+// Removes the denied inserts, along with the rest of their transactions,
+// returning the lowest removed HLC
+fn reject_inserts<K, V>(
+    inserts: &mut Vec<Insert<K, V>>,
+    txns: &[TxnRange],
+    denied: impl Fn(&Insert<K, V>) -> bool,
+    mut reject: impl FnMut(Insert<K, V>),
+) -> Option<Hlc> {
+    let denied: Vec<Hlc> = inserts
+        .iter()
+        .filter(|insert| denied(insert))
+        .map(|insert| insert.hlc)
+        .collect();
+    if denied.is_empty() {
+        return None;
+    }
+
+    // reject whole transactions, so that each stays atomic
+    let mut ranges: Vec<TxnRange> = txns
+        .iter()
+        .filter(|txn| {
+            denied
+                .iter()
+                .any(|hlc| txn.start <= *hlc && *hlc <= txn.end)
+        })
+        .copied()
+        .collect();
+    ranges.extend(denied.iter().map(|hlc| TxnRange {
+        start: *hlc,
+        end: *hlc,
+        deletes: false,
+    }));
+
+    let mut lowest = None;
+    for insert in mem::take(inserts) {
+        if ranges
+            .iter()
+            .any(|txn| txn.start <= insert.hlc && insert.hlc <= txn.end)
+        {
+            lowest = Some(lowest.map_or(insert.hlc, |lowest: Hlc| lowest.min(insert.hlc)));
+            reject(insert);
+        } else {
+            inserts.push(insert);
+        }
+    }
+    lowest
+}
+
+impl<K: Clone + Ord + ByteLen, V: Clone + ByteLen> MemStore<K, V> {
+    /// Builds a diff from an untrusted request, enforcing the store's limits.
+    /// The request and the diff's entry count are checked before any entries are cloned.
+    pub fn try_build_diff(&self, request: DiffRequest) -> Result<Diff<K, V>, LimitError> {
+        self.try_build_diff_private(&request, None, false, |_| true, false)
+    }
+
+    /// Builds a diff from a peer's untrusted request, enforcing the store's limits
+    /// and leaving out the entries the access control policy does not let the peer read
+    pub fn try_build_diff_for(
+        &self,
+        peer: &str,
+        request: DiffRequest,
+    ) -> Result<Diff<K, V>, LimitError> {
+        let reader = PeerId::from_str(peer);
+        self.try_build_diff_private(
+            &request,
+            None,
+            false,
+            |key| self.can_read(&reader, key),
+            true,
+        )
+    }
+
+    /// Builds a diff from an untrusted filtered request, enforcing the store's limits
+    pub fn try_build_filtered_diff(
+        &self,
+        request: FilteredDiffRequest<K>,
+    ) -> Result<Diff<K, V>, LimitError> {
+        self.limits.check_filter(&request.filter)?;
+        let subscription = self.subscription(&request.filter);
+        self.try_build_diff_private(
+            &request.request,
+            subscription,
+            false,
+            |key| request.filter.contains(key),
+            false,
+        )
+    }
+
+    /// Builds a diff from an untrusted subset request, enforcing the store's limits
+    pub fn try_build_subset_diff(
+        &self,
+        request: SubsetDiffRequest,
+    ) -> Result<Diff<K, V>, LimitError> {
+        self.try_build_diff_private(&request.0, None, true, |_| true, false)
+    }
+
+    fn try_build_diff_private(
+        &self,
+        request: &DiffRequest,
+        subscription: Option<&HashMap<PeerId, Hlc>>,
+        subset: bool,
+        keep: impl Fn(&K) -> bool,
+        hold: bool,
+    ) -> Result<Diff<K, V>, LimitError> {
+        self.limits.check_request(request)?;
+        let hlcs = self.diff_hlcs(request, subscription, subset);
+        let entries = hlcs
+            .iter()
+            .map(|hlcs| hlcs.inserts.len() + hlcs.deletes.len())
+            .sum();
+        self.limits.check_entries(entries)?;
+
+        let diff = self.materialize_diff(hlcs, keep, hold);
+        self.limits.check_diff(&diff)?;
+        Ok(diff)
+    }
+
+    /// Validates a diff against the local state and limits, then integrates it.
+    /// If the store has an identity, every insert must be signed by its author.
+    /// On error, the store is left unchanged.
+    pub fn try_integrate_diff(&mut self, diff: Diff<K, V>) -> Result<(), IntegrateError> {
+        self.validate_diff(&diff)?;
+        self.integrate_verified_diff(diff);
+        Ok(())
+    }
+
+    /// Validates a diff received from a peer, then integrates it like
+    /// [`MemStore::integrate_diff_from`].
+    /// On error, the store is left unchanged.
+    pub fn try_integrate_diff_from(
+        &mut self,
+        peer: &str,
+        diff: Diff<K, V>,
+    ) -> Result<Rejected<K>, IntegrateError> {
+        self.validate_diff(&diff)?;
+        Ok(self.integrate_verified_diff_from(peer, diff))
+    }
+
+    /// Validates an opset received from a peer, then integrates it like
+    /// [`MemStore::integrate_opset_from`].
+    /// On error, the store is left unchanged.
+    pub fn try_integrate_opset_from(
+        &mut self,
+        peer: &str,
+        opset: OpSet<K, V>,
+    ) -> Result<Rejected<K>, IntegrateError> {
+        self.validate_opset(&opset)?;
+        Ok(self.integrate_verified_opset_from(peer, opset))
+    }
+
+    fn validate_diff(&self, diff: &Diff<K, V>) -> Result<(), IntegrateError> {
+        self.limits.check_diff(diff)?;
+        for (peer_id, diff_peer) in &diff.0 {
+            let limit = self.hlc_limit(peer_id);
+            if diff_peer.bookmark > limit {
+                return Err(IntegrateError::FutureHlc {
+                    peer: peer_id.as_slice().to_vec(),
+                    hlc: diff_peer.bookmark.to_u64(),
+                    limit: limit.to_u64(),
+                });
             }
+            self.validate_inserts(peer_id, &diff_peer.inserts, &diff_peer.txns, limit)?;
         }
+        Ok(())
+    }
 
-        peer.bookmark = peer.bookmark.max(diff_peer.bookmark);
-        peer.bookmark
+    /// Validates an opset against the local state and limits, then integrates it.
+    /// If the store has an identity, the opset must be signed by its sender.
+    /// On error, the store is left unchanged.
+    pub fn try_integrate_opset(&mut self, opset: OpSet<K, V>) -> Result<(), IntegrateError> {
+        self.validate_opset(&opset)?;
+        self.integrate_verified_opset(opset);
+        Ok(())
     }
 
-    pub fn integrate_opset(&mut self, opset: OpSet<K, V>) {
-        let peer = self.peers.entry(opset.peer_id.clone()).or_default();
+    fn validate_opset(&self, opset: &OpSet<K, V>) -> Result<(), IntegrateError> {
+        self.limits.check_opset(opset)?;
+        self.verify_opset(opset)?;
+        let limit = self.hlc_limit(&opset.peer_id);
+        self.validate_inserts(&opset.peer_id, &opset.inserts, &opset.txns, limit)
+    }
 
-        for insert in opset.inserts {
-            let did_insert = match self.entries.entry(insert.key.clone()) {
-                btree_map::Entry::Vacant(entry) => {
-                    entry.insert(Entry {
-                        value: insert.value,
-                        author: opset.peer_id.clone(),
-                        hlc: insert.hlc,
-                    });
-                    true
-                }
-                btree_map::Entry::Occupied(mut entry) => {
-                    // replace the old entry iff the new insert follows causally
-                    let old = entry.get_mut();
-                    if old.hlc < insert.hlc || old.hlc == insert.hlc && old.author < opset.peer_id {
-                        entry.insert(Entry {
-                            value: insert.value,
-                            author: opset.peer_id.clone(),
-                            hlc: insert.hlc,
-                        });
-                        true
-                    } else {
-                        false
-                    }
-                }
-            };
+    // Returns the highest HLC a remote message may carry for the peer
+    fn hlc_limit(&self, peer_id: &PeerId) -> Hlc {
+        if *peer_id == self.local_id {
+            return self.peers[peer_id].bookmark;
+        }
+        let drift = u64::try_from(self.max_drift.as_micros()).unwrap_or(u64::MAX);
+        Hlc::new(Hlc::pt().saturating_add(drift), u16::MAX)
+    }
+
+    // Checks a single author's inserts and transaction boundaries against the local state
+    fn validate_inserts(
+        &self,
+        peer_id: &PeerId,
+        inserts: &[Insert<K, V>],
+        txns: &[TxnRange],
+        limit: Hlc,
+    ) -> Result<(), IntegrateError> {
+        let peer = self.peers.get(peer_id);
+        let bookmark = peer.map(|peer| peer.bookmark).unwrap_or_default();
+        let mut hlcs = HashSet::with_capacity(inserts.len());
+
+        for insert in inserts {
+            let hlc = insert.hlc.to_u64();
+            if insert.hlc > limit {
+                return Err(IntegrateError::FutureHlc {
+                    peer: peer_id.as_slice().to_vec(),
+                    hlc,
+                    limit: limit.to_u64(),
+                });
+            }
+            let key = peer.and_then(|peer| peer.keys.get(&insert.hlc));
+            if !hlcs.insert(insert.hlc) || key.is_some_and(|key| *key != insert.key) {
+                return Err(IntegrateError::DuplicateHlc {
+                    peer: peer_id.as_slice().to_vec(),
+                    hlc,
+                });
+            }
+            if insert.hlc <= bookmark && key.is_none() {
+                return Err(IntegrateError::DeletedInsert {
+                    peer: peer_id.as_slice().to_vec(),
+                    hlc,
+                    bookmark: bookmark.to_u64(),
+                });
+            }
+            self.verify_insert(peer_id, insert)?;
+        }
+
+        for txn in txns {
+            if txn.start > txn.end {
+                return Err(IntegrateError::InvalidTxn {
+                    peer: peer_id.as_slice().to_vec(),
+                    start: txn.start.to_u64(),
+                    end: txn.end.to_u64(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "signing")]
+impl<K: Clone + Ord + AsRef<[u8]>, V: Clone + AsRef<[u8]>> MemStore<K, V> {
+    /// Creates a new, empty CRDT whose peer ID is derived from the identity's public key.
+    /// Local inserts and opsets are signed, and only signed inserts and opsets are
+    /// integrated: the `try_integrate_*` methods reject the others, and the other
+    /// integration methods drop them.
+    pub fn from_identity(identity: Identity) -> Self {
+        let signer = Signer::new(identity);
+        let mut store = MemStore::new(
+            std::str::from_utf8(signer.peer_id().as_slice()).expect("peer IDs are hex"),
+        );
+        store.signer = Some(signer);
+        store
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<K: Clone + Ord + Send + Sync, V: Clone + Send + Sync> MemStore<K, V> {
+    /// Builds a diff from the request object like [`MemStore::build_diff`],
+    /// computing each peer's HLCs and cloning its entries in parallel
+    pub fn build_diff_par(&self, request: DiffRequest) -> Diff<K, V> {
+        let diff_hlcs: Vec<DiffHlcs<'_, K>> = self
+            .peers
+            .par_iter()
+            .filter_map(|(peer_id, peer_state)| DiffHlcs::new(peer_id, peer_state, &request, None))
+            .collect();
+
+        Diff(
+            diff_hlcs
+                .into_par_iter()
+                .map(|hlcs| {
+                    let inserts: Vec<Insert<K, V>> = hlcs
+                        .inserts
+                        .iter()
+                        .collect::<Vec<u64>>()
+                        .into_par_iter()
+                        .filter_map(|hlc| self.indexed_insert(hlcs.peer_state, hlc))
+                        .map(InsertRef::into_owned)
+                        .collect();
+                    let txns = hlcs
+                        .peer_state
+                        .txns_covering(inserts.iter().map(|insert| insert.hlc));
+                    let state = DiffPeerState {
+                        inserts,
+                        deletes: hlcs.deletes,
+                        bookmark: hlcs.peer_state.bookmark,
+                        txns,
+                    };
+                    (hlcs.peer_id.clone(), state)
+                })
+                .collect(),
+        )
+    }
 
-            if did_insert {
-                peer.index.insert(insert.hlc.to_u64());
-                peer.keys.insert(insert.hlc, insert.key);
+    /// Integrates a diff like [`MemStore::integrate_diff`], first dropping the inserts that
+    /// lose to existing entries and sorting the rest by key in parallel.
+    /// The result matches `integrate_diff` as long as no author reuses an HLC for another key.
+    pub fn integrate_diff_par(&mut self, mut diff: Diff<K, V>) {
+        self.verify_diff(&mut diff);
+
+        // integrate deletes
+        for (peer_id, diff_peer) in &diff.0 {
+            self.integrate_deletes(peer_id, &diff_peer.deletes);
+        }
+
+        // partition inserts from their peers' bookmarks and transactions
+        let mut peers = Vec::with_capacity(diff.0.len());
+        let mut peer_inserts = Vec::with_capacity(diff.0.len());
+        for (peer_id, diff_peer) in diff.0 {
+            if peer_id == self.local_id {
+                continue;
+            }
+            self.peers.entry(peer_id.clone()).or_default();
+            peer_inserts.push(diff_peer.inserts);
+            peers.push((peer_id, diff_peer.txns, diff_peer.bookmark));
+        }
+
+        // drop losing inserts, then apply the rest in key order
+        let entries = &self.entries;
+        let peer_ids: Vec<&PeerId> = peers.iter().map(|(peer_id, _, _)| peer_id).collect();
+        let mut inserts: Vec<(usize, Insert<K, V>)> = peer_inserts
+            .into_par_iter()
+            .enumerate()
+            .flat_map(|(i, inserts)| {
+                let peer_id = peer_ids[i];
+                inserts
+                    .into_par_iter()
+                    .filter(move |insert| {
+                        entries
+                            .get(&insert.key)
+                            .is_none_or(|entry| entry.overwritten_by(peer_id, insert.hlc))
+                    })
+                    .map(move |insert| (i, insert))
+            })
+            .collect();
+        inserts.par_sort_unstable_by(|(_, a), (_, b)| a.key.cmp(&b.key));
+        for (i, insert) in inserts {
+            self.integrate_insert(&peers[i].0, insert);
+        }
+
+        for (peer_id, txns, bookmark) in peers {
+            let peer = self
+                .peers
+                .get_mut(&peer_id)
+                .expect("invalid peer state accounting");
+            for txn in txns {
+                peer.add_txn(txn);
             }
+            peer.bookmark = peer.bookmark.max(bookmark);
+        }
+    }
+}
+
+impl<K, V> DiffPeerState<K, V> {
+    // Holds the bookmark below the lowest removed insert, so that it can be sent again
+    fn hold_bookmark(&mut self, lowest: Option<Hlc>) {
+        if let Some(hlc) = lowest {
+            let below = Hlc::from_u64(hlc.to_u64().saturating_sub(1));
+            self.bookmark = self.bookmark.min(below);
+        }
+    }
+}
+
+impl<V> Entry<V> {
+    // Returns `true` if an insert by the peer at the HLC follows the entry causally
+    fn overwritten_by(&self, peer_id: &PeerId, hlc: Hlc) -> bool {
+        self.hlc < hlc || self.hlc == hlc && self.author < *peer_id
+    }
+}
+
+impl<'a, K> DiffHlcs<'a, K> {
+    // Computes the HLCs a diff carries for a peer, or `None` if the remote already has
+    // its state.
+    // Deletes are bounded by the full bookmark, or by a subscription's bookmark
+    // for requests covering only its keys.
+    fn new(
+        peer_id: &'a PeerId,
+        peer_state: &'a PeerState<K>,
+        request: &DiffRequest,
+        subscription: Option<&HashMap<PeerId, Hlc>>,
+    ) -> Option<Self> {
+        let mut hlcs = DiffHlcs {
+            peer_id,
+            peer_state,
+            inserts: RoaringTreemap::new(),
+            deletes: RoaringTreemap::new(),
+        };
+
+        if let Some(request) = request.0.get(peer_id) {
+            let index = request.index.bitmap();
+
+            // inserts: all e ⊂ (local - remote) AND e > remote.max
+            hlcs.inserts = &peer_state.index - &*index;
+            hlcs.inserts.remove_range(0..=request.bookmark.to_u64());
+
+            // deletes: all e ⊂ (remote - local) AND e ≤ local.max
+            let bookmark = subscription
+                .and_then(|bookmarks| bookmarks.get(peer_id))
+                .map_or(peer_state.bookmark, |bookmark| {
+                    peer_state.bookmark.max(*bookmark)
+                });
+            hlcs.deletes = &*index - &peer_state.index;
+            hlcs.deletes
+                .remove_range((Excluded(bookmark.to_u64()), Unbounded));
+        } else {
+            // inserts: all e ⊂ local
+            hlcs.inserts = peer_state.index.clone();
+        }
+
+        let bookmark_changed = request
+            .0
+            .get(peer_id)
+            .is_none_or(|request| request.bookmark < peer_state.bookmark);
+        (!hlcs.inserts.is_empty() || !hlcs.deletes.is_empty() || bookmark_changed).then_some(hlcs)
+    }
+
+    // Completes the peer's diff state with the inserts materialized from its HLCs
+    fn into_state<V>(self, inserts: Vec<InsertRef<'a, K, V>>) -> DiffPeerStateRef<'a, K, V> {
+        let txns = self
+            .peer_state
+            .txns_covering(inserts.iter().map(|insert| insert.hlc));
+        DiffPeerStateRef {
+            inserts,
+            deletes: self.deletes,
+            bookmark: self.peer_state.bookmark,
+            txns,
         }
     }
 }
 
 impl<K> PeerState<K> {
+    // Serializes the index only if it changed since the last request
     fn diff_request(&self) -> DiffRequestPeerState {
+        let index = if self.index.is_empty() {
+            RequestIndex::Bitmap(RoaringTreemap::new())
+        } else {
+            let bytes = self.request.get_or_init(|| {
+                let mut bytes = Vec::with_capacity(self.index.serialized_size());
+                self.index
+                    .serialize_into(&mut bytes)
+                    .expect("writing to a vec cannot fail");
+                bytes.into()
+            });
+            RequestIndex::Serialized(bytes.clone())
+        };
         DiffRequestPeerState {
-            index: self.index.clone(),
+            index,
             bookmark: self.bookmark,
         }
     }
+
+    // Adds an HLC to the peer state
+    fn insert(&mut self, hlc: Hlc, key: K) {
+        self.index.insert(hlc.to_u64());
+        self.keys.insert(hlc, key);
+        self.request.take();
+    }
+
+    // Removes an HLC from the peer state, returning its key
+    fn remove(&mut self, hlc: Hlc) -> Option<K> {
+        self.index.remove(hlc.to_u64());
+        self.request.take();
+        if let Some(txn) = self.txn(hlc)
+            && self.txn_len(txn) == 0
+        {
+            self.txns.remove(&txn.start);
+        }
+        self.keys.remove(&hlc)
+    }
+
+    // Records a transaction boundary, as long as some of its inserts remain
+    fn add_txn(&mut self, txn: TxnRange) {
+        let spans = txn.start < txn.end || txn.start == txn.end && txn.deletes;
+        if spans && self.txn_len(txn) > 0 {
+            self.txns.insert(txn.start, txn);
+        }
+    }
+
+    // Returns the transaction containing the HLC, if any
+    fn txn(&self, hlc: Hlc) -> Option<TxnRange> {
+        self.txns
+            .range(..=hlc)
+            .next_back()
+            .map(|(_, txn)| *txn)
+            .filter(|txn| hlc <= txn.end)
+    }
+
+    // Returns the number of the transaction's inserts still in the index
+    fn txn_len(&self, txn: TxnRange) -> u64 {
+        let start = txn.start.to_u64();
+        self.index.rank(txn.end.to_u64()) - self.index.rank(start)
+            + self.index.contains(start) as u64
+    }
+
+    // Returns the transactions covering a sorted sequence of HLCs
+    fn txns_covering(&self, hlcs: impl Iterator<Item = Hlc>) -> Vec<TxnRange> {
+        let mut txns: Vec<TxnRange> = Vec::new();
+        for hlc in hlcs {
+            if txns.last().is_some_and(|txn| hlc <= txn.end) {
+                continue;
+            }
+            txns.extend(self.txn(hlc));
+        }
+        txns
+    }
 }
 
 impl<'a, K: Ord + Clone, V: Clone> MemStoreTxn<'a, K, V> {
     /// Inserts a key-value pair into the CRDT
     pub fn insert(&mut self, key: K, value: V) {
+        self.deletes.remove(&key);
         self.inserts.insert(key, value);
     }
 
@@ -401,19 +1666,119 @@ impl<'a, K: Ord + Clone, V: Clone> MemStoreTxn<'a, K, V> {
         self.deletes.insert(key.to_owned());
     }
 
+    /// Returns a reference to the value corresponding to the key, including uncommitted changes
+    pub fn get(&self, key: &K) -> Option<&V> {
+        if self.deletes.contains(key) {
+            return None;
+        }
+        self.inserts.get(key).or_else(|| self.store.get(key))
+    }
+
+    /// Returns the committed metadata of the entry at the key
+    pub fn get_meta(&self, key: &K) -> Option<EntryMeta> {
+        self.store.get_meta(key)
+    }
+
+    /// Validates that the entry's committed metadata matches `expected`, where `None`
+    /// expects the key to be absent. The transaction's own changes are ignored, as in
+    /// `KVStoreTxn::validate`, so compare-and-swap behaves alike on both stores.
+    /// The check is not repeated at commit, since the transaction holds the store
+    /// exclusively and no other write can land in between.
+    pub fn validate(&self, key: &K, expected: Option<&EntryMeta>) -> Result<(), Conflict> {
+        if self.get_meta(key).as_ref() != expected {
+            return Err(Conflict);
+        }
+        Ok(())
+    }
+
+    /// Inserts a key-value pair iff the entry's metadata matches `expected`,
+    /// as checked by [`MemStoreTxn::validate`]
+    pub fn compare_and_swap(
+        &mut self,
+        key: K,
+        expected: Option<&EntryMeta>,
+        value: V,
+    ) -> Result<(), Conflict> {
+        self.validate(&key, expected)?;
+        self.insert(key, value);
+        Ok(())
+    }
+
+    /// Inserts a key-value pair iff the key is absent from the committed state
+    pub fn insert_if_absent(&mut self, key: K, value: V) -> Result<(), Conflict> {
+        self.compare_and_swap(key, None, value)
+    }
+
+    /// Creates a savepoint that the transaction can later roll back to
+    pub fn savepoint(&mut self) -> Savepoint {
+        let savepoint = Savepoint(NEXT_SAVEPOINT.fetch_add(1, Ordering::Relaxed));
+        self.savepoints
+            .push((savepoint, self.inserts.clone(), self.deletes.clone()));
+        savepoint
+    }
+
+    /// Rolls back all changes made since the savepoint was created.
+    /// The savepoint remains active; savepoints created after it are released.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) -> Result<(), ReleasedSavepoint> {
+        let position = self.savepoint_position(savepoint)?;
+        self.savepoints.truncate(position + 1);
+        let (_, inserts, deletes) = &self.savepoints[position];
+        self.inserts = inserts.clone();
+        self.deletes = deletes.clone();
+        Ok(())
+    }
+
+    /// Releases the savepoint and all savepoints created after it, keeping their changes
+    pub fn release(&mut self, savepoint: Savepoint) -> Result<(), ReleasedSavepoint> {
+        let position = self.savepoint_position(savepoint)?;
+        self.savepoints.truncate(position);
+        Ok(())
+    }
+
+    fn savepoint_position(&self, savepoint: Savepoint) -> Result<usize, ReleasedSavepoint> {
+        self.savepoints
+            .iter()
+            .position(|(active, _, _)| *active == savepoint)
+            .ok_or(ReleasedSavepoint)
+    }
+
     /// Aborts the transaction
     pub fn abort(self) {}
 
     /// Commits the transaction
     pub fn commit(self) {
-        let mut hlc = self.store.mut_local_peer_state().bookmark.next();
-        for (key, value) in self.inserts {
-            self.store.insert_private(key, value, Some(hlc));
-            hlc = hlc.inc();
-        }
-        for key in self.deletes {
-            self.store.remove(&key);
+        let MemStoreTxn {
+            store,
+            inserts,
+            deletes,
+            ..
+        } = self;
+        store.commit_private(inserts, deletes);
+    }
+
+    /// Commits the transaction, returning the opset produced by the transaction.
+    /// The returned opset is independent of store-wide opset tracking.
+    pub fn commit_with_ops(self) -> OpSet<K, V> {
+        let MemStoreTxn {
+            store,
+            inserts,
+            deletes,
+            ..
+        } = self;
+
+        // collect the transaction's ops into a fresh opset
+        let outer = store.opset.replace(OpSet::new(store.local_id.clone()));
+        store.commit_private(inserts, deletes);
+        let mut ops = store.opset.take().expect("transaction opset must exist");
+
+        // forward the transaction's ops to the store-wide opset, if tracked
+        store.opset = outer;
+        if let Some(opset) = &mut store.opset {
+            opset.merge(ops.clone());
         }
+
+        store.sign_opset(&mut ops);
+        ops
     }
 }
 
@@ -426,3 +1791,992 @@ impl<'a, K: Ord, V> Entries<'a, K, V> {
         self.0.iter().map(|(key, entry)| (key, &entry.value))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        access::{KeyRangeAccess, Permissions, prefix},
+        filter::KeyFilter,
+    };
+
+    #[test]
+    fn test_commit_with_ops() {
+        let mut a = MemStore::new("alice");
+        let mut b = MemStore::new("bob");
+        a.insert(1u32, 10u32);
+        a.insert(2, 20);
+        b.integrate_diff(a.build_diff(b.request_diff()));
+
+        let mut txn = a.begin();
+        txn.insert(2, 21);
+        txn.insert(3, 30);
+        txn.remove(&1);
+        let ops = txn.commit_with_ops();
+
+        assert_eq!(ops.inserts.len(), 2);
+        assert_eq!(ops.inserts[1].hlc, ops.inserts[0].hlc.inc());
+        assert_eq!(ops.deletes[&a.local_id].len(), 2);
+
+        b.integrate_opset(ops);
+        assert_eq!(a.entries(), b.entries());
+    }
+
+    #[test]
+    fn test_opset_deletes_own_inserts() {
+        let mut a = MemStore::new("alice").with_opset();
+        let mut txn = a.begin();
+        txn.insert(1u32, 10u32);
+        txn.insert(2, 20);
+        txn.commit();
+        let mut txn = a.begin();
+        txn.remove(&1);
+        txn.insert(2, 21);
+        txn.insert(3, 30);
+        txn.remove(&3);
+        txn.commit();
+        a.remove(&2);
+        assert!(a.is_empty());
+
+        // the opset inserts every key, then deletes them all
+        let opset = a.take_opset();
+        assert_eq!(opset.inserts.len(), 3);
+        let mut b = MemStore::new("bob");
+        b.integrate_opset(opset.clone());
+        assert!(b.is_empty());
+        assert!(b.check_invariants().is_ok());
+
+        let mut c = MemStore::new("carol");
+        c.try_integrate_opset(opset).unwrap();
+        assert!(c.is_empty());
+        assert!(c.check_invariants().is_ok());
+    }
+
+    #[test]
+    fn test_commit_with_ops_forwards_to_store_opset() {
+        let mut a = MemStore::new("alice").with_opset();
+        a.insert(1u32, 10u32);
+
+        let mut txn = a.begin();
+        txn.insert(2, 20);
+        let ops = txn.commit_with_ops();
+        assert_eq!(ops.inserts.len(), 1);
+
+        let opset = a.take_opset();
+        assert_eq!(opset.inserts.len(), 2);
+    }
+
+    #[test]
+    fn test_savepoints() {
+        let mut a = MemStore::new("alice");
+        a.insert(0u32, 0u32);
+
+        let mut txn = a.begin();
+        txn.insert(1, 1);
+        let outer = txn.savepoint();
+        txn.insert(2, 2);
+        txn.remove(&0);
+        txn.insert(3, 3);
+        txn.rollback_to(outer).unwrap();
+        txn.insert(4, 4);
+        let nested = txn.savepoint();
+        txn.insert(5, 5);
+        txn.release(nested).unwrap();
+        txn.commit();
+
+        let entries: Vec<_> = a.entries().iter().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(entries, vec![(0, 0), (1, 1), (4, 4), (5, 5)]);
+    }
+
+    #[test]
+    fn test_rollback_to_released_savepoint() {
+        let mut a: MemStore<u32, u32> = MemStore::new("alice");
+        let mut txn = a.begin();
+        let outer = txn.savepoint();
+        let inner = txn.savepoint();
+        txn.rollback_to(outer).unwrap();
+        assert!(txn.rollback_to(inner).is_err());
+        assert!(txn.release(inner).is_err());
+        txn.release(outer).unwrap();
+        assert!(txn.release(outer).is_err());
+        txn.commit();
+
+        // savepoints of another transaction are rejected
+        let mut txn = a.begin();
+        assert!(txn.rollback_to(outer).is_err());
+        assert!(txn.release(outer).is_err());
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let mut a = MemStore::new("alice");
+        let mut b = MemStore::new("bob");
+        assert!(a.insert_if_absent(1u32, 1u32).is_ok());
+        assert!(a.insert_if_absent(1, 2).is_err());
+
+        let meta = a.get_meta(&1).unwrap();
+        assert_eq!(a.compare_and_swap(1, Some(&meta), 3).unwrap(), Some(1));
+        assert!(a.compare_and_swap(1, Some(&meta), 4).is_err());
+
+        // a locally-integrated remote write invalidates the read
+        let meta = a.get_meta(&1).unwrap();
+        Hlc::set_mock_pt(u64::MAX);
+        b.insert(1, 5);
+        Hlc::unset_mock_pt();
+        a.integrate_diff(b.build_diff(a.request_diff()));
+        assert!(a.compare_and_swap(1, Some(&meta), 6).is_err());
+        assert_eq!(a.get(&1), Some(&5));
+    }
+
+    #[test]
+    fn test_transaction_validation() {
+        let mut a = MemStore::new("alice");
+        a.insert(1u32, 1u32);
+        let stale = a.get_meta(&1).unwrap();
+        a.insert(1, 2);
+
+        // checks compare against the committed state, ignoring the transaction's own changes
+        let mut txn = a.begin();
+        let meta = txn.get_meta(&1).unwrap();
+        assert!(txn.validate(&1, Some(&stale)).is_err());
+        txn.insert(2, 1);
+        txn.insert_if_absent(2, 2).unwrap();
+        txn.compare_and_swap(1, Some(&meta), 3).unwrap();
+        txn.compare_and_swap(1, Some(&meta), 4).unwrap();
+        txn.remove(&1);
+        txn.validate(&1, Some(&meta)).unwrap();
+        assert!(txn.insert_if_absent(1, 5).is_err());
+        txn.commit();
+        assert_eq!(a.get(&1), None);
+        assert_eq!(a.get(&2), Some(&2));
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        let mut a = MemStore::new("alice");
+        let mut b = MemStore::new("bob");
+        a.insert(1u32, 1u32);
+        a.insert(2, 2);
+        b.integrate_diff(a.build_diff(b.request_diff()));
+        b.insert(2, 3);
+        assert!(a.check_invariants().is_ok());
+        assert!(b.check_invariants().is_ok());
+
+        // corrupt the accounting of a remote entry
+        let alice = a.local_id.clone();
+        let hlc = b.entries[&1].hlc;
+        let state = b.peers.get_mut(&alice).unwrap();
+        state.index.remove(hlc.to_u64());
+        state.index.insert(hlc.to_u64() + 100);
+
+        let report = b.check_invariants();
+        assert_eq!(
+            report.violations,
+            vec![
+                Violation::MissingIndex {
+                    peer: b"alice".to_vec(),
+                    hlc: hlc.to_u64(),
+                },
+                Violation::OrphanedIndex {
+                    peer: b"alice".to_vec(),
+                    hlc: hlc.to_u64() + 100,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_diff_keeps_transactions_whole() {
+        let mut a = MemStore::new("alice");
+        let mut b = MemStore::new("bob");
+        a.insert(0u32, 0u32);
+        let mut txn = a.begin();
+        for i in 1..=5 {
+            txn.insert(i, i);
+        }
+        txn.commit();
+        a.insert(6, 6);
+        a.insert(7, 7);
+
+        let chunks = a.build_diff(b.request_diff()).split(2);
+        let sizes: Vec<usize> = chunks
+            .iter()
+            .map(|chunk| chunk.0.values().map(|state| state.inserts.len()).sum())
+            .collect();
+        assert_eq!(sizes, vec![1, 5, 2]);
+
+        for chunk in chunks {
+            b.integrate_diff(chunk);
+        }
+        assert_eq!(a.entries(), b.entries());
+        assert_eq!(b.peers[&a.local_id].txns.len(), 1);
+        assert_eq!(b.peers[&a.local_id].bookmark, a.peers[&a.local_id].bookmark);
+    }
+
+    #[test]
+    fn test_split_diff_keeps_transaction_deletes() {
+        let mut a = MemStore::new("alice");
+        let mut b = MemStore::new("bob");
+        for i in 0..4u32 {
+            a.insert(i, i);
+        }
+        b.integrate_diff(a.build_diff(b.request_diff()));
+
+        // a multi-key transaction and a single-key transaction, both with deletes,
+        // among plain inserts
+        a.insert(10, 10);
+        let mut txn = a.begin();
+        txn.insert(20, 20);
+        txn.insert(21, 21);
+        txn.remove(&0);
+        txn.remove(&1);
+        txn.commit();
+        a.insert(11, 11);
+        let mut txn = a.begin();
+        txn.insert(30, 30);
+        txn.remove(&2);
+        txn.commit();
+        a.insert(12, 12);
+        assert_eq!(a.peers[&a.local_id].txns.len(), 2);
+
+        let chunks = a.build_diff(b.request_diff()).split(1);
+        let sizes: Vec<usize> = chunks
+            .iter()
+            .map(|chunk| chunk.0.values().map(|state| state.inserts.len()).sum())
+            .collect();
+        assert_eq!(sizes, vec![1, 1, 4]);
+        for chunk in chunks {
+            b.integrate_diff(chunk);
+            let first = [
+                b.get(&20),
+                b.get(&21),
+                b.get(&0).xor(Some(&0)),
+                b.get(&1).xor(Some(&1)),
+            ];
+            assert!(first.iter().all(Option::is_some) || first.iter().all(Option::is_none));
+            let second = [b.get(&30), b.get(&2).xor(Some(&2))];
+            assert!(second.iter().all(Option::is_some) || second.iter().all(Option::is_none));
+            assert!(b.check_invariants().is_ok());
+        }
+        assert_eq!(a.entries(), b.entries());
+    }
+
+    #[test]
+    fn test_transaction_boundary_pruned() {
+        let mut a = MemStore::new("alice");
+        let mut txn = a.begin();
+        txn.insert(1u32, 1u32);
+        txn.insert(2, 2);
+        txn.commit();
+        assert_eq!(a.peers[&a.local_id].txns.len(), 1);
+
+        a.remove(&1);
+        assert_eq!(a.peers[&a.local_id].txns.len(), 1);
+        a.insert(2, 3);
+        assert!(a.peers[&a.local_id].txns.is_empty());
+    }
+
+    #[test]
+    fn test_malformed_diff() {
+        let mut a = MemStore::new("alice");
+        let mut b = MemStore::new("bob");
+        a.insert(1u32, 1u32);
+        a.insert(2, 2);
+        b.integrate_diff(a.build_diff(b.request_diff()));
+
+        // a request covering a huge HLC range of an unknown peer
+        let mut request = b.request_diff();
+        let hlc = a.peers[&a.local_id].bookmark;
+        let mut index = RoaringTreemap::new();
+        index.insert_range(hlc.to_u64() - (1 << 31)..hlc.to_u64() + (1 << 31));
+        request.0.insert(
+            PeerId::from_str("mallory"),
+            DiffRequestPeerState {
+                index: RequestIndex::Bitmap(index.clone()),
+                bookmark: Hlc::from_u64(u64::MAX),
+            },
+        );
+        assert!(a.build_diff(request).is_empty());
+
+        // a diff that deletes a huge HLC range, pushes bookmarks to their maximum
+        // and reuses an HLC for another key
+        let max = Hlc::from_u64(u64::MAX);
+        let mut diff = Diff(HashMap::default());
+        diff.0.insert(
+            a.local_id.clone(),
+            DiffPeerState {
+                inserts: Vec::default(),
+                deletes: index.clone(),
+                bookmark: max,
+                txns: vec![TxnRange {
+                    start: max,
+                    end: Hlc::default(),
+                    deletes: false,
+                }],
+            },
+        );
+        diff.0.insert(
+            b.local_id.clone(),
+            DiffPeerState {
+                inserts: Vec::default(),
+                deletes: RoaringTreemap::new(),
+                bookmark: max,
+                txns: Vec::default(),
+            },
+        );
+        diff.0.insert(
+            PeerId::from_str("mallory"),
+            DiffPeerState {
+                inserts: vec![
+                    Insert {
+                        key: 3,
+                        value: 3,
+                        hlc,
+                        signature: None,
+                    },
+                    Insert {
+                        key: 4,
+                        value: 4,
+                        hlc,
+                        signature: None,
+                    },
+                ],
+                deletes: RoaringTreemap::new(),
+                bookmark: hlc,
+                txns: Vec::default(),
+            },
+        );
+        let bookmark = b.peers[&b.local_id].bookmark;
+        b.integrate_diff(diff);
+        assert_eq!(b.entries().iter().collect::<Vec<_>>(), vec![(&3, &3)]);
+        assert!(b.check_invariants().is_ok());
+
+        // the local bookmark is not moved by a remote, so local writes keep distinct HLCs
+        assert_eq!(b.peers[&b.local_id].bookmark, bookmark);
+        let mut opset = OpSet::new(b.local_id.clone());
+        opset.add_insert(Insert {
+            key: 6,
+            value: 6,
+            hlc: max,
+            signature: None,
+        });
+        b.integrate_opset(opset);
+        assert_eq!(b.peers[&b.local_id].bookmark, bookmark);
+        b.insert(5, 5);
+        b.insert(6, 6);
+        assert!(b.peers[&b.local_id].bookmark < max);
+        assert_ne!(b.entries[&5].hlc, b.entries[&6].hlc);
+        assert!(b.check_invariants().is_ok());
+        a.integrate_diff(b.build_diff(a.request_diff()));
+        assert_eq!(a.entries(), b.entries());
+
+        let mut opset = OpSet::new(b.local_id.clone());
+        opset.deletes.insert(PeerId::from_str("mallory"), index);
+        a.integrate_opset(opset);
+        assert_eq!(
+            a.entries().iter().collect::<Vec<_>>(),
+            vec![(&5, &5), (&6, &6)]
+        );
+    }
+
+    #[test]
+    fn test_try_integrate_diff() {
+        let mut a = MemStore::new("alice");
+        let mut b = MemStore::new("bob");
+        a.insert(1u32, 1u32);
+        let stale = a.build_diff(b.request_diff());
+        b.try_integrate_diff(a.build_diff(b.request_diff()))
+            .unwrap();
+        assert_eq!(a.entries(), b.entries());
+
+        // a stale diff resurrecting a deleted insert
+        b.remove(&1);
+        let request = b.request_diff();
+        let err = b.try_integrate_diff(stale.clone()).unwrap_err();
+        assert!(matches!(err, IntegrateError::DeletedInsert { .. }), "{err}");
+        assert!(b.is_empty());
+        assert_eq!(b.request_diff(), request);
+
+        // a diff reusing an HLC for another key
+        a.insert(2, 2);
+        let mut diff = a.build_diff(b.request_diff());
+        let state = diff.0.get_mut(&a.local_id).unwrap();
+        let mut insert = state.inserts[0].clone();
+        insert.key = 3;
+        state.inserts.push(insert);
+        let err = b.try_integrate_diff(diff).unwrap_err();
+        assert!(matches!(err, IntegrateError::DuplicateHlc { .. }), "{err}");
+        assert_eq!(b.request_diff(), request);
+
+        // a bookmark of the local peer beyond the local bookmark
+        let future = Hlc::new(Hlc::pt() + 2 * DEFAULT_MAX_DRIFT.as_micros() as u64, 0);
+        b.insert(4, 4);
+        a.try_integrate_diff(b.build_diff(a.request_diff()))
+            .unwrap();
+        let mut diff = Diff(HashMap::default());
+        diff.0.insert(
+            b.local_id.clone(),
+            DiffPeerState {
+                inserts: Vec::default(),
+                deletes: RoaringTreemap::new(),
+                bookmark: b.peers[&b.local_id].bookmark.inc(),
+                txns: Vec::default(),
+            },
+        );
+        let err = b.try_integrate_diff(diff.clone()).unwrap_err();
+        assert!(matches!(err, IntegrateError::FutureHlc { .. }), "{err}");
+        let request = b.request_diff();
+        b.integrate_diff(diff);
+        assert_eq!(b.request_diff(), request);
+
+        // a far-future bookmark, unless the max drift allows it
+        let mut diff = a.build_diff(b.request_diff());
+        diff.0.get_mut(&a.local_id).unwrap().bookmark = future;
+        let err = b.try_integrate_diff(diff.clone()).unwrap_err();
+        assert!(matches!(err, IntegrateError::FutureHlc { .. }), "{err}");
+        let mut b = b.with_max_drift(3 * DEFAULT_MAX_DRIFT);
+        b.try_integrate_diff(diff).unwrap();
+    }
+
+    #[test]
+    fn test_try_integrate_opset() {
+        let mut a = MemStore::new("alice").with_opset();
+        let mut b = MemStore::new("bob");
+        let mut txn = a.begin();
+        txn.insert(1u32, 1u32);
+        txn.insert(2, 2);
+        txn.commit();
+        let ops = a.take_opset();
+
+        let mut inverted = ops.clone();
+        inverted.txns[0] = TxnRange {
+            start: inverted.txns[0].end,
+            end: inverted.txns[0].start,
+            deletes: false,
+        };
+        let err = b.try_integrate_opset(inverted).unwrap_err();
+        assert!(matches!(err, IntegrateError::InvalidTxn { .. }), "{err}");
+        assert!(b.is_empty());
+
+        // duplicated delivery is accepted
+        b.try_integrate_opset(ops.clone()).unwrap();
+        b.try_integrate_opset(ops.clone()).unwrap();
+        assert_eq!(a.entries(), b.entries());
+
+        // delivery after a state sync and delete is not
+        b.integrate_diff(a.build_diff(b.request_diff()));
+        b.remove(&1);
+        let err = b.try_integrate_opset(ops).unwrap_err();
+        assert!(matches!(err, IntegrateError::DeletedInsert { .. }), "{err}");
+        assert_eq!(b.len(), 1);
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            max_entries: 4,
+            max_peers: 2,
+            max_value_size: 8,
+            ..Limits::default()
+        };
+        let mut a = MemStore::new("alice").with_opset().with_limits(limits);
+        let mut b = MemStore::new("bob").with_limits(limits);
+        for key in 0..4u32 {
+            a.insert(key, vec![0u8; 8]);
+        }
+        b.try_integrate_diff(a.try_build_diff(b.request_diff()).unwrap())
+            .unwrap();
+        a.take_opset();
+
+        // the diff would carry too many entries, and is rejected before any are cloned
+        a.insert(4, vec![0u8; 8]);
+        let mut request = b.request_diff();
+        request.0.remove(&a.local_id);
+        let err = a.try_build_diff(request).err().unwrap();
+        assert!(
+            matches!(err, LimitError::TooManyEntries { entries: 5, .. }),
+            "{err}"
+        );
+
+        // a request with too many peers
+        let mut request = b.request_diff();
+        request.0.insert(
+            PeerId::from_str("mallory"),
+            DiffRequestPeerState {
+                index: RequestIndex::Bitmap(RoaringTreemap::new()),
+                bookmark: Hlc::default(),
+            },
+        );
+        let err = a.try_build_diff(request).err().unwrap();
+        assert!(
+            matches!(err, LimitError::TooManyPeers { peers: 3, .. }),
+            "{err}"
+        );
+
+        // values that are too large, in a diff and an opset
+        a.insert(5, vec![0u8; 9]);
+        let diff = a.build_diff(b.request_diff());
+        let err = b.try_integrate_diff(diff).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                IntegrateError::Limit(LimitError::ValueTooLarge { size: 9, .. })
+            ),
+            "{err}"
+        );
+        let err = b.try_integrate_opset(a.take_opset()).unwrap_err();
+        assert!(matches!(err, IntegrateError::Limit(_)), "{err}");
+        assert_eq!(b.len(), 4);
+
+        let limits = Limits {
+            max_request_bytes: 8,
+            ..Limits::default()
+        };
+        let a = a.with_limits(limits);
+        let err = a.try_build_diff(b.request_diff()).err().unwrap();
+        assert!(matches!(err, LimitError::RequestTooLarge { .. }), "{err}");
+
+        // a filtered request with too many ranges, and a message that is too long
+        let limits = Limits {
+            max_message_bytes: 8,
+            max_filter_ranges: 2,
+            ..Limits::default()
+        };
+        let a = a.with_limits(limits);
+        let filter = (0..3).fold(KeyFilter::new(), |filter, key| filter.range(key..=key));
+        let err = a
+            .try_build_filtered_diff(b.request_filtered_diff(&filter))
+            .err()
+            .unwrap();
+        assert!(
+            matches!(err, LimitError::TooManyRanges { ranges: 3, .. }),
+            "{err}"
+        );
+        assert!(limits.check_message(&[0; 8]).is_ok());
+        let err = limits.check_message(&[0; 9]).unwrap_err();
+        assert!(
+            matches!(err, LimitError::MessageTooLarge { bytes: 9, .. }),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_access_control() {
+        let access = KeyRangeAccess::new()
+            .grant("alice", prefix(b"a/"), Permissions::ALL)
+            .grant("alice", prefix(b"shared/"), Permissions::READ)
+            .grant("bob", prefix(b"shared/"), Permissions::ALL);
+        let mut hub = MemStore::new("hub").with_access_control(access);
+        let mut a = MemStore::new("alice").with_opset();
+        let mut b = MemStore::new("bob");
+        let key = |key: &str| key.as_bytes().to_vec();
+
+        // writes outside alice's ranges are rejected, along with the rest of their transaction
+        a.insert(key("a/1"), 1u32);
+        let mut txn = a.begin();
+        txn.insert(key("a/2"), 2);
+        txn.insert(key("b/2"), 2);
+        txn.commit();
+        let rejected = hub.integrate_diff_from("alice", a.build_diff(hub.request_diff()));
+        let keys: Vec<_> = rejected.inserts.iter().map(|op| op.key.clone()).collect();
+        assert_eq!(keys, [key("a/2"), key("b/2")]);
+        assert_eq!(hub.len(), 1);
+        assert!(hub.check_invariants().is_ok());
+
+        // the bookmark is held below rejected inserts, so they are sent again
+        let rejected = hub.integrate_diff_from("alice", a.build_diff(hub.request_diff()));
+        assert_eq!(rejected.inserts.len(), 2);
+
+        // opsets are checked the same way
+        a.insert(key("shared/1"), 1);
+        let rejected = hub.integrate_opset_from("alice", a.take_opset());
+        let keys: Vec<_> = rejected.inserts.iter().map(|op| op.key.clone()).collect();
+        assert_eq!(keys, [key("a/2"), key("b/2"), key("shared/1")]);
+
+        // reads are filtered, and deletes outside the delete ranges are rejected
+        b.insert(key("shared/1"), 1);
+        assert!(
+            hub.integrate_diff_from("bob", b.build_diff(hub.request_diff()))
+                .is_empty()
+        );
+        a.integrate_diff(hub.build_diff_for("alice", a.request_diff()));
+        assert_eq!(a.get(&key("shared/1")), Some(&1));
+        let mut c = MemStore::<Vec<u8>, u32>::new("carol");
+        c.integrate_diff(hub.build_diff_for("carol", c.request_diff()));
+        assert!(c.is_empty());
+
+        // carol's bookmarks are held below the left out entries, so they are sent once
+        // carol is granted access
+        let access = KeyRangeAccess::new()
+            .grant("alice", prefix(b"a/"), Permissions::ALL)
+            .grant("alice", prefix(b"shared/"), Permissions::READ)
+            .grant("bob", prefix(b"shared/"), Permissions::ALL)
+            .grant("carol", prefix(b"shared/"), Permissions::READ);
+        let mut hub = hub.with_access_control(access);
+        c.integrate_diff(hub.build_diff_for("carol", c.request_diff()));
+        assert_eq!(c.get(&key("shared/1")), Some(&1));
+        assert!(c.check_invariants().is_ok());
+
+        a.remove(&key("shared/1"));
+        let rejected = hub.integrate_diff_from("alice", a.build_diff(hub.request_diff()));
+        let keys: Vec<_> = rejected.deletes.iter().map(|op| op.key.clone()).collect();
+        assert_eq!(keys, [key("shared/1")]);
+        assert_eq!(hub.get(&key("shared/1")), Some(&1));
+        assert!(hub.check_invariants().is_ok());
+    }
+
+    // Syncs `to` from `from` with hash-based reconciliation,
+    // returning the number of hash rounds and the region request's index size
+    fn reconcile(from: &MemStore<u32, u32>, to: &mut MemStore<u32, u32>) -> (usize, usize) {
+        let mut reconciler = to.reconciler();
+        let mut request = reconciler.request();
+        let mut rounds = 1;
+        while let Some(next) = reconciler.refine(from.compare_hashes(&request)) {
+            request = next;
+            rounds += 1;
+        }
+        let request = reconciler.finish();
+        let index_size = request.index_size();
+        to.integrate_diff(from.build_region_diff(request));
+        (rounds, index_size)
+    }
+
+    #[test]
+    fn test_hash_reconciliation() {
+        let mut a = MemStore::new("alice");
+        let mut b = MemStore::new("bob");
+        let mut c = MemStore::new("carol");
+
+        // pin the clock, so that the entries spread over the same HLC windows on every run
+        let pt = 1_628_999_999_946_752;
+        for key in 0..5_000 {
+            Hlc::set_mock_pt(pt + ((key as u64 / 100) << 16));
+            a.insert(key, key);
+        }
+        b.integrate_diff(a.build_diff(b.request_diff()));
+
+        Hlc::set_mock_pt(pt + (100 << 16));
+        a.insert(0, 1);
+        a.remove(&1);
+        b.insert(2, 3);
+        b.remove(&3);
+        c.insert(10_000, 0);
+        b.integrate_diff(c.build_diff(b.request_diff()));
+
+        let full_size = a.request_diff().index_size();
+        let (rounds, index_size) = reconcile(&b, &mut a);
+        assert!(rounds <= 4, "{rounds}");
+        assert!(index_size * 10 < full_size, "{index_size} vs {full_size}");
+        reconcile(&a, &mut b);
+        assert_eq!(a.entries(), b.entries());
+        assert_eq!(a.len(), 4_999);
+        assert!(a.check_invariants().is_ok());
+
+        // identical states only exchange top-level hashes
+        assert_eq!(reconcile(&a, &mut b).0, 1);
+        Hlc::unset_mock_pt();
+    }
+
+    #[test]
+    fn test_partial_replication() {
+        let key = |key: &str| key.as_bytes().to_vec();
+        let filter = KeyFilter::new().prefix(b"t1/");
+        let mut hub = MemStore::new("hub");
+        let mut a = MemStore::new("alice");
+        let mut b = MemStore::new("bob");
+        b.insert(key("t1/x"), 1u32);
+        b.insert(key("t1/y"), 2);
+        b.insert(key("t2/x"), 3);
+        hub.integrate_diff(b.build_diff(hub.request_diff()));
+
+        // alice pulls only the keys matching her filter
+        let request = a.request_filtered_diff(&filter);
+        a.integrate_filtered_diff(&filter, hub.build_filtered_diff(request));
+        assert_eq!(a.len(), 2);
+
+        // alice's deletes are pushed without deleting keys outside her filter,
+        // even through full syncs
+        a.remove(&key("t1/x"));
+        a.insert(key("t1/z"), 4);
+        hub.integrate_diff(a.build_filtered_diff(hub.request_filtered_diff(&filter)));
+        hub.integrate_diff(a.build_diff(hub.request_diff()));
+        let keys: Vec<_> = hub.entries().iter().map(|(key, _)| key.clone()).collect();
+        assert_eq!(keys, [key("t1/y"), key("t1/z"), key("t2/x")]);
+
+        // remote deletes and inserts reach alice's subset
+        b.integrate_diff(hub.build_diff(b.request_diff()));
+        b.remove(&key("t1/y"));
+        b.insert(key("t1/w"), 5);
+        b.insert(key("t2/y"), 6);
+        hub.integrate_diff(b.build_diff(hub.request_diff()));
+        let request = a.request_filtered_diff(&filter);
+        a.integrate_filtered_diff(&filter, hub.build_filtered_diff(request));
+        let keys: Vec<_> = a.entries().iter().map(|(key, _)| key.clone()).collect();
+        assert_eq!(keys, [key("t1/w"), key("t1/z")]);
+
+        // unsubscribing evicts remote entries without syncing deletes
+        a.unsubscribe(&filter);
+        assert_eq!(a.len(), 1);
+        assert!(a.check_invariants().is_ok());
+        hub.integrate_diff(a.build_diff(hub.request_diff()));
+        assert_eq!(hub.len(), 4);
+
+        // full bookmarks were never advanced, so a full pull fetches everything
+        a.integrate_diff(hub.build_diff(a.request_diff()));
+        assert_eq!(a.entries(), hub.entries());
+        assert!(hub.check_invariants().is_ok());
+    }
+
+    #[test]
+    fn test_unsubscribe_keeps_opset_entries() {
+        let filter = KeyFilter::new().range(100u32..200);
+        let mut hub = MemStore::new("hub");
+        let mut a = MemStore::new("alice");
+        let mut b = MemStore::new("bob").with_opset();
+        b.insert(1, 10u32);
+        b.insert(2, 20);
+        a.integrate_opset(b.take_opset());
+
+        // a filter that was never subscribed to is ignored
+        a.unsubscribe(&filter);
+        assert_eq!(a.len(), 2);
+
+        // only the entries that arrived through the filter are evicted
+        hub.insert(100, 100);
+        hub.insert(101, 101);
+        let request = a.request_filtered_diff(&filter);
+        a.integrate_filtered_diff(&filter, hub.build_filtered_diff(request));
+        b.insert(150, 150);
+        a.integrate_opset(b.take_opset());
+        assert_eq!(a.len(), 5);
+        a.unsubscribe(&filter);
+        let keys: Vec<_> = a.entries().iter().map(|(key, _)| *key).collect();
+        assert_eq!(keys, [1, 2, 150]);
+        assert!(a.check_invariants().is_ok());
+    }
+
+    #[test]
+    fn test_cached_requests() {
+        let mut a = MemStore::new("alice");
+        let mut b = MemStore::new("bob");
+        let mut c = MemStore::new("carol");
+        a.insert(1u32, 1u32);
+        b.insert(2, 2);
+        c.integrate_diff(a.build_diff(c.request_diff()));
+        c.integrate_diff(b.build_diff(c.request_diff()));
+
+        // unchanged indexes reuse their serialized bytes
+        let index =
+            |request: &DiffRequest, peer: &str| match &request.0[&PeerId::from_str(peer)].index {
+                RequestIndex::Serialized(bytes) => bytes.clone(),
+                RequestIndex::Bitmap(_) => panic!("index is not cached"),
+            };
+        let request = c.request_diff();
+        assert_eq!(
+            index(&request, "alice").as_ptr(),
+            index(&c.request_diff(), "alice").as_ptr()
+        );
+
+        // changes to a peer's index only refresh that peer's bytes
+        a.insert(3, 3);
+        a.remove(&1);
+        c.integrate_diff(a.build_diff(c.request_diff()));
+        let updated = c.request_diff();
+        assert_ne!(index(&request, "alice"), index(&updated, "alice"));
+        assert_eq!(
+            index(&request, "bob").as_ptr(),
+            index(&updated, "bob").as_ptr()
+        );
+        assert!(c.check_invariants().is_ok());
+
+        // cached bytes match the bitmap they were serialized from
+        let mut decoded = updated.clone();
+        for state in decoded.0.values_mut() {
+            state.index = RequestIndex::Bitmap(state.index.bitmap().into_owned());
+        }
+        assert_eq!(decoded, updated);
+        assert_eq!(decoded.index_size(), updated.index_size());
+        assert!(c.build_diff(decoded).is_empty());
+
+        // a subset request only covers the listed peers, requesting unknown peers from scratch
+        let mut d = MemStore::new("dave");
+        let request = d.request_subset_diff(&["bob"]);
+        d.integrate_diff(c.build_subset_diff(request));
+        assert_eq!(d.len(), 1);
+        assert!(d.get(&2).is_some());
+        let request = d.request_subset_diff(&["alice", "bob"]);
+        d.integrate_diff(c.build_subset_diff(request));
+        assert_eq!(d.entries(), c.entries());
+        assert!(
+            c.build_subset_diff(d.request_subset_diff(&["alice"]))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_borrowed_diff() {
+        let mut a = MemStore::new("alice");
+        let mut b = MemStore::new("bob");
+        let mut txn = a.begin();
+        txn.insert(b"a".to_vec(), b"1".to_vec());
+        txn.insert(b"b".to_vec(), b"2".to_vec());
+        txn.commit();
+        a.insert(b"c".to_vec(), b"3".to_vec());
+
+        // a borrowed diff encodes exactly like an owned one
+        fn encode<T: serde::Serialize>(diff: &T) -> Vec<u8> {
+            let mut bytes = Vec::new();
+            ciborium::into_writer(diff, &mut bytes).unwrap();
+            bytes
+        }
+        let borrowed = a.build_diff_ref(b.request_diff());
+        let owned = a.build_diff(b.request_diff());
+        assert_eq!(encode(&borrowed), encode(&owned));
+
+        let decoded: Diff<Vec<u8>, Vec<u8>> =
+            ciborium::from_reader(&encode(&borrowed)[..]).unwrap();
+        b.integrate_diff(decoded);
+        assert_eq!(a.entries(), b.entries());
+        assert!(a.build_diff_ref(b.request_diff()).is_empty());
+
+        // transaction boundaries and deletes survive the conversion to an owned diff
+        let mut c = MemStore::new("carol");
+        let diff = a.build_diff_ref(c.request_diff()).into_owned();
+        assert_eq!(diff.0[&a.local_id].txns.len(), 1);
+        c.integrate_diff(diff);
+        a.remove(&b"a".to_vec());
+        let diff = a.build_diff_ref(c.request_diff()).into_owned();
+        assert_eq!(diff.0[&a.local_id].deletes.len(), 1);
+        c.integrate_diff(diff);
+        assert_eq!(a.entries(), c.entries());
+    }
+
+    #[test]
+    fn test_bytes_store() {
+        let mut a = BytesMemStore::new("alice").with_opset();
+        let mut b = BytesMemStore::new("bob");
+        let value = Bytes::from(vec![7; 1024]);
+        a.insert(Bytes::from_static(b"a"), value.clone());
+
+        // diffs and opsets share the store's buffers
+        let diff = a.build_diff(b.request_diff());
+        assert_eq!(
+            diff.0[&a.local_id].inserts[0].value.as_ptr(),
+            value.as_ptr()
+        );
+        let opset = a.take_opset();
+        assert_eq!(opset.inserts[0].value.as_ptr(), value.as_ptr());
+
+        // values survive the wire as byte strings
+        let mut encoded = Vec::new();
+        ciborium::into_writer(&diff, &mut encoded).unwrap();
+        b.integrate_diff(ciborium::from_reader(&encoded[..]).unwrap());
+        assert_eq!(a.entries(), b.entries());
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_parallel_sync() {
+        let mut a = MemStore::new("alice");
+        let mut b = MemStore::new("bob");
+        let mut c = MemStore::new("carol");
+        let mut txn = a.begin();
+        for i in 0..1000u32 {
+            txn.insert(i, i);
+        }
+        txn.commit();
+        for i in 500..1500u32 {
+            b.insert(i, i + 1);
+        }
+        c.integrate_diff(b.build_diff(c.request_diff()));
+        a.remove(&0);
+
+        // parallel diffs match sequential ones
+        let diff = a.build_diff_par(c.request_diff());
+        let mut d = MemStore::new("dave");
+        d.integrate_diff(c.build_diff(d.request_diff()));
+        d.integrate_diff(a.build_diff(d.request_diff()));
+        c.integrate_diff_par(diff);
+        assert_eq!(c.entries(), d.entries());
+        assert!(c.check_invariants().is_ok());
+
+        // losing inserts are dropped, and bookmarks and transactions still advance
+        a.integrate_diff_par(c.build_diff_par(a.request_diff()));
+        assert_eq!(a.entries(), c.entries());
+        assert!(a.build_diff(c.request_diff()).is_empty());
+        assert!(c.build_diff(a.request_diff()).is_empty());
+        assert!(a.check_invariants().is_ok());
+    }
+
+    #[cfg(feature = "signing")]
+    #[test]
+    fn test_signed_sync() {
+        let mut a: MemStore<Vec<u8>, Vec<u8>> =
+            MemStore::from_identity(Identity::generate()).with_opset();
+        let mut b = MemStore::from_identity(Identity::generate());
+        let mut c = MemStore::from_identity(Identity::generate());
+        a.insert(b"a".to_vec(), b"1".to_vec());
+        a.insert(b"b".to_vec(), b"2".to_vec());
+
+        // signatures are relayed alongside entries
+        b.try_integrate_diff(a.build_diff(b.request_diff()))
+            .unwrap();
+        c.try_integrate_diff(b.build_diff(c.request_diff()))
+            .unwrap();
+        assert_eq!(a.entries(), c.entries());
+
+        // an insert forged by a relay
+        let mut diff = b.build_diff(MemStore::<Vec<u8>, Vec<u8>>::new("d").request_diff());
+        let insert = &mut diff.0.get_mut(&a.local_id).unwrap().inserts[0];
+        insert.value = b"forged".to_vec();
+        let mut d = MemStore::from_identity(Identity::generate());
+        let err = d.try_integrate_diff(diff).unwrap_err();
+        assert!(
+            matches!(err, IntegrateError::InvalidSignature { .. }),
+            "{err}"
+        );
+        assert!(d.is_empty());
+
+        // unsigned inserts, and inserts claiming an ID that is not a public key
+        let mut unsigned = MemStore::new("mallory");
+        unsigned.insert(b"c".to_vec(), b"3".to_vec());
+        let err = d
+            .try_integrate_diff(unsigned.build_diff(d.request_diff()))
+            .unwrap_err();
+        assert!(
+            matches!(err, IntegrateError::InvalidSignature { .. }),
+            "{err}"
+        );
+
+        // opsets are signed by their sender
+        let mut ops = a.take_opset();
+        let mut tampered = ops.clone();
+        tampered.inserts.pop();
+        let err = c.try_integrate_opset(tampered).unwrap_err();
+        assert!(
+            matches!(err, IntegrateError::InvalidOpsetSignature { .. }),
+            "{err}"
+        );
+        c.try_integrate_opset(ops.clone()).unwrap();
+        ops.merge(OpSet::new(a.local_id.clone()));
+        let err = c.try_integrate_opset(ops).unwrap_err();
+        assert!(
+            matches!(err, IntegrateError::InvalidOpsetSignature { .. }),
+            "{err}"
+        );
+
+        // the plain integration paths drop forged inserts and opsets, and the author's
+        // bookmark is held below them so that the genuine inserts are still sent
+        let mut diff = a.build_diff(d.request_diff());
+        diff.0.get_mut(&a.local_id).unwrap().inserts[1].value = b"forged".to_vec();
+        d.integrate_diff(diff);
+        assert_eq!(d.len(), 1);
+        d.integrate_diff(a.build_diff(d.request_diff()));
+        assert_eq!(a.entries(), d.entries());
+        a.insert(b"c".to_vec(), b"3".to_vec());
+        let mut ops = a.take_opset();
+        ops.inserts[0].value = b"forged".to_vec();
+        d.integrate_opset(ops);
+        assert_eq!(d.len(), 2);
+    }
+}
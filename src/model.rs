@@ -0,0 +1,390 @@
+//! Model-checked specification of the state sync protocol.
+//!
+//! [`Spec`] is an executable model of the diff protocol over small sets:
+//! - inserts: `e ∈ (B − A) ∧ e > A.max`
+//! - deletes: `e ∈ (A − B) ∧ e ≤ B.max`
+//!
+//! Stateright exhaustively explores every interleaving of writes and syncs across a
+//! few peers and keys. Each reachable state is checked for convergence and for
+//! no-resurrection, and the real [`MemStore`] is replayed along the same path and
+//! checked against the spec.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    hash::{Hash, Hasher},
+};
+
+use stateright::{Checker, Model, Property};
+
+use crate::{hlc::Hlc, memory::MemStore, peer_id::PeerId};
+
+const NAMES: [&str; 3] = ["a", "b", "c"];
+
+// physical time is fixed, so each peer's n-th local HLC is `PT + n - 1`
+const PT: u64 = 1_628_999_999_946_752;
+
+/// Spec HLCs count each author's writes from 1
+type SpecHlc = u64;
+
+/// Unique ID of each write, used as its value
+type WriteId = u32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Action {
+    Insert { peer: usize, key: u8 },
+    Remove { peer: usize, key: u8 },
+    Sync { from: usize, to: usize },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct SpecEntry {
+    author: usize,
+    hlc: SpecHlc,
+    write: WriteId,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+struct SpecPeer {
+    entries: BTreeMap<u8, SpecEntry>,
+    index: BTreeMap<usize, BTreeSet<SpecHlc>>,
+    bookmarks: BTreeMap<usize, SpecHlc>,
+    // writes this peer has held and then dropped
+    dropped: BTreeSet<WriteId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Spec {
+    peers: Vec<SpecPeer>,
+    writes: WriteId,
+    removes: u32,
+}
+
+/// Explored state: the spec, plus the path that first reached it.
+/// Only the spec takes part in state deduplication.
+#[derive(Debug, Clone)]
+struct State {
+    spec: Spec,
+    history: Vec<Action>,
+}
+
+impl PartialEq for State {
+    fn eq(&self, other: &Self) -> bool {
+        self.spec == other.spec
+    }
+}
+
+impl Eq for State {}
+
+impl Hash for State {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.spec.hash(state);
+    }
+}
+
+struct SyncModel {
+    peers: usize,
+    keys: u8,
+    max_writes: WriteId,
+    max_removes: u32,
+}
+
+impl SpecPeer {
+    fn insert(&mut self, author: usize, key: u8, hlc: SpecHlc, write: WriteId) {
+        let entry = SpecEntry { author, hlc, write };
+        if let Some(old) = self.entries.insert(key, entry) {
+            self.drop_entry(&old);
+        }
+        self.index.entry(author).or_default().insert(hlc);
+    }
+
+    fn remove(&mut self, key: u8) {
+        if let Some(old) = self.entries.remove(&key) {
+            self.drop_entry(&old);
+        }
+    }
+
+    fn drop_entry(&mut self, entry: &SpecEntry) {
+        if let Some(index) = self.index.get_mut(&entry.author) {
+            index.remove(&entry.hlc);
+        }
+        self.dropped.insert(entry.write);
+    }
+
+    fn index(&self, author: usize) -> BTreeSet<SpecHlc> {
+        self.index.get(&author).cloned().unwrap_or_default()
+    }
+}
+
+impl Spec {
+    fn apply(&mut self, action: Action) {
+        match action {
+            Action::Insert { peer, key } => {
+                self.writes += 1;
+                let state = &mut self.peers[peer];
+                let hlc = state.bookmarks.get(&peer).copied().unwrap_or_default() + 1;
+                state.bookmarks.insert(peer, hlc);
+                state.insert(peer, key, hlc, self.writes);
+            }
+            Action::Remove { peer, key } => {
+                self.removes += 1;
+                self.peers[peer].remove(key);
+            }
+            Action::Sync { from, to } => self.sync(from, to),
+        }
+    }
+
+    // `to` requests a diff from `from` and integrates it
+    fn sync(&mut self, from: usize, to: usize) {
+        let remote = self.peers[to].clone();
+        let local = &self.peers[from];
+
+        let mut inserts = Vec::new();
+        let mut deletes = Vec::new();
+        for (&author, &bookmark) in &local.bookmarks {
+            let local_index = local.index(author);
+            let remote_index = remote.index(author);
+            let remote_max = remote.bookmarks.get(&author).copied().unwrap_or_default();
+
+            // inserts: e ∈ (local − remote) ∧ e > remote.max
+            for &hlc in local_index.difference(&remote_index) {
+                if hlc > remote_max {
+                    let (key, entry) = local
+                        .entries
+                        .iter()
+                        .find(|(_, entry)| entry.author == author && entry.hlc == hlc)
+                        .expect("indexed HLC must have an entry");
+                    inserts.push((*key, entry.clone()));
+                }
+            }
+
+            // deletes: e ∈ (remote − local) ∧ e ≤ local.max
+            for &hlc in remote_index.difference(&local_index) {
+                if hlc <= bookmark {
+                    deletes.push((author, hlc));
+                }
+            }
+        }
+        let bookmarks = local.bookmarks.clone();
+
+        let remote = &mut self.peers[to];
+        for (author, hlc) in deletes {
+            let key = remote
+                .entries
+                .iter()
+                .find(|(_, entry)| entry.author == author && entry.hlc == hlc)
+                .map(|(key, _)| *key);
+            if let Some(key) = key {
+                remote.remove(key);
+            }
+        }
+        for (key, entry) in inserts {
+            // last writer wins, with ties broken by author
+            let wins = remote
+                .entries
+                .get(&key)
+                .is_none_or(|old| (old.hlc, old.author) < (entry.hlc, entry.author));
+            if wins {
+                remote.insert(entry.author, key, entry.hlc, entry.write);
+            }
+        }
+        for (author, bookmark) in bookmarks {
+            let remote_bookmark = remote.bookmarks.entry(author).or_default();
+            *remote_bookmark = (*remote_bookmark).max(bookmark);
+        }
+    }
+
+    // Runs pairwise syncs until every peer has heard from every other
+    fn full_sync(&mut self) {
+        for _ in 0..2 {
+            for from in 0..self.peers.len() {
+                for to in 0..self.peers.len() {
+                    if from != to {
+                        self.sync(from, to);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Model for SyncModel {
+    type State = State;
+    type Action = Action;
+
+    fn init_states(&self) -> Vec<State> {
+        vec![State {
+            spec: Spec {
+                peers: vec![SpecPeer::default(); self.peers],
+                writes: 0,
+                removes: 0,
+            },
+            history: Vec::new(),
+        }]
+    }
+
+    fn actions(&self, state: &State, actions: &mut Vec<Action>) {
+        for peer in 0..self.peers {
+            for key in 0..self.keys {
+                if state.spec.writes < self.max_writes {
+                    actions.push(Action::Insert { peer, key });
+                }
+                if state.spec.removes < self.max_removes
+                    && state.spec.peers[peer].entries.contains_key(&key)
+                {
+                    actions.push(Action::Remove { peer, key });
+                }
+            }
+            for to in 0..self.peers {
+                if peer != to {
+                    actions.push(Action::Sync { from: peer, to });
+                }
+            }
+        }
+    }
+
+    fn next_state(&self, last: &State, action: Action) -> Option<State> {
+        let mut spec = last.spec.clone();
+        spec.apply(action);
+        if spec == last.spec {
+            return None;
+        }
+        let mut history = last.history.clone();
+        history.push(action);
+        Some(State { spec, history })
+    }
+
+    fn properties(&self) -> Vec<Property<Self>> {
+        vec![
+            Property::always("no resurrection", |_, state: &State| {
+                state.spec.peers.iter().all(|peer| {
+                    peer.entries
+                        .values()
+                        .all(|entry| !peer.dropped.contains(&entry.write))
+                })
+            }),
+            Property::always("convergence", |_, state: &State| {
+                let mut spec = state.spec.clone();
+                spec.full_sync();
+                spec.peers
+                    .windows(2)
+                    .all(|pair| pair[0].entries == pair[1].entries)
+            }),
+            Property::always("memstore refines spec", |_, state: &State| {
+                let mut stores = replay(&state.history, state.spec.peers.len());
+                let refines = matches(&stores, &state.spec);
+
+                let mut spec = state.spec.clone();
+                spec.full_sync();
+                full_sync(&mut stores);
+                refines && matches(&stores, &spec)
+            }),
+        ]
+    }
+}
+
+type Store = MemStore<u8, WriteId>;
+
+// Replays a path of actions against real stores with a fixed physical time
+fn replay(history: &[Action], peers: usize) -> Vec<Store> {
+    Hlc::set_mock_pt(PT);
+    let mut stores: Vec<Store> = NAMES[..peers]
+        .iter()
+        .map(|name| MemStore::new(name))
+        .collect();
+    let mut writes = 0;
+    for action in history {
+        match *action {
+            Action::Insert { peer, key } => {
+                writes += 1;
+                stores[peer].insert(key, writes);
+            }
+            Action::Remove { peer, key } => {
+                stores[peer].remove(&key);
+            }
+            Action::Sync { from, to } => sync(&mut stores, from, to),
+        }
+    }
+    stores
+}
+
+fn sync(stores: &mut [Store], from: usize, to: usize) {
+    let diff = stores[from].build_diff(stores[to].request_diff());
+    stores[to].integrate_diff(diff);
+}
+
+fn full_sync(stores: &mut [Store]) {
+    for _ in 0..2 {
+        for from in 0..stores.len() {
+            for to in 0..stores.len() {
+                if from != to {
+                    sync(stores, from, to);
+                }
+            }
+        }
+    }
+}
+
+// Checks that each store holds the spec's entries, indexes and bookmarks
+fn matches(stores: &[Store], spec: &Spec) -> bool {
+    let real_hlc = |hlc: SpecHlc| PT + hlc - 1;
+    stores.iter().zip(&spec.peers).all(|(store, peer)| {
+        let entries: BTreeMap<u8, WriteId> =
+            store.entries().iter().map(|(k, v)| (*k, *v)).collect();
+        let spec_entries: BTreeMap<u8, WriteId> = peer
+            .entries
+            .iter()
+            .map(|(key, entry)| (*key, entry.write))
+            .collect();
+
+        let request = store.request_diff();
+        let peer_states_match =
+            NAMES[..spec.peers.len()]
+                .iter()
+                .enumerate()
+                .all(|(author, name)| {
+                    let state = request.0.get(&PeerId::from_str(name));
+                    let index: BTreeSet<u64> = state
+                        .map(|state| state.index.bitmap().iter().collect())
+                        .unwrap_or_default();
+                    let spec_index: BTreeSet<u64> =
+                        peer.index(author).into_iter().map(real_hlc).collect();
+                    let bookmark = state
+                        .map(|state| state.bookmark.to_u64())
+                        .unwrap_or_default();
+                    let spec_bookmark = peer
+                        .bookmarks
+                        .get(&author)
+                        .map(|hlc| real_hlc(*hlc))
+                        .unwrap_or_default();
+                    index == spec_index && bookmark == spec_bookmark
+                });
+
+        entries == spec_entries && peer_states_match && store.check_invariants().is_ok()
+    })
+}
+
+#[test]
+fn test_two_peers() {
+    let model = SyncModel {
+        peers: 2,
+        keys: 2,
+        max_writes: 3,
+        max_removes: 2,
+    };
+    let checker = model.checker().spawn_bfs().join();
+    checker.assert_properties();
+    assert!(checker.unique_state_count() > 100);
+}
+
+#[test]
+fn test_three_peers() {
+    let model = SyncModel {
+        peers: 3,
+        keys: 2,
+        max_writes: 2,
+        max_removes: 1,
+    };
+    let checker = model.checker().spawn_bfs().join();
+    checker.assert_properties();
+    assert!(checker.unique_state_count() > 1_000);
+}
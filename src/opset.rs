@@ -1,33 +1,48 @@
 use std::collections::{HashMap, hash_map::Entry};
 
+use bytes::Bytes;
 use roaring::RoaringTreemap;
 use serde::{Deserialize, Serialize};
 
-use crate::{diff::Insert, hlc::Hlc, peer_id::PeerId};
+#[cfg(feature = "memory")]
+use crate::hlc::Hlc;
+use crate::{
+    diff::{Insert, TxnRange},
+    peer_id::PeerId,
+};
 
 /// Op set for incremental diffs during a live connection
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct OpSet<K, V> {
     pub(crate) peer_id: PeerId,
     pub(crate) inserts: Vec<Insert<K, V>>,
     pub(crate) deletes: HashMap<PeerId, RoaringTreemap>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) txns: Vec<TxnRange>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) signature: Option<Bytes>,
 }
 
 impl<K, V> OpSet<K, V> {
+    #[cfg(feature = "memory")]
     pub(crate) fn new(peer_id: PeerId) -> Self {
         OpSet {
             peer_id,
             inserts: Vec::default(),
             deletes: HashMap::default(),
+            txns: Vec::default(),
+            signature: None,
         }
     }
 
     /// Adds an insert to the op set
+    #[cfg(feature = "memory")]
     pub(crate) fn add_insert(&mut self, item: Insert<K, V>) {
         self.inserts.push(item);
     }
 
     /// Adds a delete to the op set
+    #[cfg(feature = "memory")]
     pub(crate) fn add_delete(&mut self, peer_id: PeerId, hlc: Hlc) {
         self.deletes
             .entry(peer_id)
@@ -35,9 +50,18 @@ impl<K, V> OpSet<K, V> {
             .insert(hlc.to_u64());
     }
 
-    /// Merge one op set into another
+    /// Adds a transaction boundary to the op set
+    #[cfg(feature = "memory")]
+    pub(crate) fn add_txn(&mut self, txn: TxnRange) {
+        self.txns.push(txn);
+    }
+
+    /// Merge one op set into another.
+    /// Merging drops the op set's signature.
     pub fn merge(&mut self, mut other: OpSet<K, V>) {
+        self.signature = None;
         self.inserts.append(&mut other.inserts);
+        self.txns.append(&mut other.txns);
         for (peer_id, other_treemap) in other.deletes {
             match self.deletes.entry(peer_id) {
                 Entry::Occupied(mut entry) => {
@@ -7,10 +7,12 @@ use serde::{Deserialize, Serialize};
 pub(crate) struct PeerId(Bytes);
 
 impl PeerId {
+    #[cfg(any(feature = "memory", test))]
     pub fn from_str(id: &str) -> Self {
         PeerId(Bytes::copy_from_slice(id.as_bytes()))
     }
 
+    #[cfg(feature = "memory")]
     #[inline]
     pub fn as_slice(&self) -> &[u8] {
         &self.0
@@ -24,6 +26,13 @@ impl From<Vec<u8>> for PeerId {
     }
 }
 
+impl From<Bytes> for PeerId {
+    #[inline]
+    fn from(eid: Bytes) -> Self {
+        PeerId(eid)
+    }
+}
+
 impl Debug for PeerId {
     #[inline]
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -0,0 +1,386 @@
+//! Hash-based reconciliation.
+//!
+//! A [`DiffRequest`] carries each peer's full index, which is wasteful for peers with
+//! huge state and few differences. Hash-based reconciliation instead narrows down the
+//! differing HLC ranges in a few rounds, then requests a diff of those ranges only:
+//!
+//! 1. The requester sends [`Reconciler::request`], hashing the HLC ranges of each peer's
+//!    index at the top level of a range tree. Each level narrows ranges by 16 bits down to
+//!    single roaring containers, then by 8 bits.
+//! 2. The responder answers with [`MemStore::compare_hashes`], listing the ranges whose
+//!    hashes differ.
+//! 3. The requester refines differing ranges into hashes of their sub-ranges with
+//!    [`Reconciler::refine`], until every differing range is either at the deepest level
+//!    or holds few local HLCs. This takes at most four rounds.
+//! 4. The requester sends [`Reconciler::finish`], carrying its index within the differing
+//!    ranges only, and the responder answers with [`MemStore::build_region_diff`], which
+//!    follows the `build_diff` logic restricted to those ranges.
+//!
+//! Range hashes are sums of mixed HLCs, so they are cheap to compute but are not meant
+//! to resist peers that forge collisions.
+//!
+//! [`MemStore::compare_hashes`]: crate::memory::MemStore::compare_hashes
+//! [`MemStore::build_region_diff`]: crate::memory::MemStore::build_region_diff
+
+use std::collections::{BTreeMap, HashMap};
+#[cfg(feature = "memory")]
+use std::convert::Infallible;
+#[cfg(feature = "kv")]
+use std::ops::RangeInclusive;
+
+use roaring::RoaringTreemap;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    diff::{DiffRequest, DiffRequestPeerState, RequestIndex},
+    hlc::Hlc,
+    peer_id::PeerId,
+};
+
+// Bits below a range's prefix at each level of the range tree.
+// Level 3 ranges are single roaring containers.
+const SHIFTS: [u32; 5] = [64, 48, 32, 16, 8];
+
+// Bits below a roaring container's prefix
+#[cfg(feature = "kv")]
+pub(crate) const CONTAINER_SHIFT: u32 = SHIFTS[3];
+
+// Deepest level of the range tree
+const MAX_LEVEL: u8 = 4;
+
+// Differing ranges holding at most this many local HLCs are sent as explicit indexes
+const LEAF_SIZE: u64 = 64;
+
+/// Hashes of HLC ranges, for one reconciliation round
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HashRequest(pub(crate) HashMap<PeerId, Vec<RangeHashes>>);
+
+/// HLC ranges whose hashes differ, for one reconciliation round
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HashResponse(pub(crate) HashMap<PeerId, Vec<HlcRange>>);
+
+/// State diff request restricted to the HLC ranges found to differ
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RegionDiffRequest {
+    pub(crate) request: DiffRequest,
+    pub(crate) regions: HashMap<PeerId, Vec<HlcRange>>,
+}
+
+// Hashes of the non-empty sub-ranges of a range, by the sub-range's digit
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct RangeHashes {
+    range: HlcRange,
+    children: Vec<(u16, u64)>,
+}
+
+// HLC range of a node in the range tree.
+// A node covers the HLCs whose bits above its level's shift equal `prefix`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct HlcRange {
+    level: u8,
+    prefix: u64,
+}
+
+/// Requester's side of a reconciliation, over a snapshot of its peer states
+pub struct Reconciler<'a> {
+    peers: HashMap<&'a PeerId, (&'a RoaringTreemap, Hlc)>,
+    regions: HashMap<PeerId, Vec<HlcRange>>,
+}
+
+impl HashRequest {
+    /// Returns the size of the hashes, in bytes
+    pub fn hash_size(&self) -> usize {
+        self.0
+            .values()
+            .flatten()
+            .map(|hashes| size_of::<HlcRange>() + hashes.children.len() * size_of::<(u16, u64)>())
+            .sum()
+    }
+}
+
+impl RegionDiffRequest {
+    /// Returns the index size, in bytes
+    pub fn index_size(&self) -> usize {
+        self.request.index_size()
+    }
+}
+
+impl HlcRange {
+    const ROOT: HlcRange = HlcRange {
+        level: 0,
+        prefix: 0,
+    };
+
+    fn child(self, digit: u16) -> HlcRange {
+        let level = self.level + 1;
+        let width = self.shift() - SHIFTS[level as usize];
+        HlcRange {
+            level,
+            prefix: (self.prefix << width) | digit as u64,
+        }
+    }
+
+    fn shift(self) -> u32 {
+        SHIFTS[self.level as usize]
+    }
+
+    pub(crate) fn start(self) -> u64 {
+        self.prefix.checked_shl(self.shift()).unwrap_or(0)
+    }
+
+    pub(crate) fn end(self) -> u64 {
+        self.start() | u64::MAX.checked_shr(64 - self.shift()).unwrap_or(0)
+    }
+
+    /// Returns the roaring container prefixes the range spans
+    #[cfg(feature = "kv")]
+    pub(crate) fn containers(self) -> RangeInclusive<u64> {
+        self.start() >> CONTAINER_SHIFT..=self.end() >> CONTAINER_SHIFT
+    }
+
+    // The level may not exceed the deepest level, and the prefix must fit the level
+    fn is_valid(self) -> bool {
+        self.level <= MAX_LEVEL && self.prefix.checked_shr(64 - self.shift()).unwrap_or(0) == 0
+    }
+}
+
+impl<'a> Reconciler<'a> {
+    #[cfg(feature = "memory")]
+    pub(crate) fn new(
+        peers: impl IntoIterator<Item = (&'a PeerId, &'a RoaringTreemap, Hlc)>,
+    ) -> Self {
+        Reconciler {
+            peers: peers
+                .into_iter()
+                .map(|(peer_id, index, bookmark)| (peer_id, (index, bookmark)))
+                .collect(),
+            regions: HashMap::default(),
+        }
+    }
+
+    /// Returns the first round's request, hashing the top-level ranges of every peer
+    pub fn request(&self) -> HashRequest {
+        HashRequest(
+            self.peers
+                .iter()
+                .map(|(peer_id, (index, _))| {
+                    let hashes = RangeHashes {
+                        range: HlcRange::ROOT,
+                        children: child_hashes(index, HlcRange::ROOT),
+                    };
+                    ((*peer_id).clone(), vec![hashes])
+                })
+                .collect(),
+        )
+    }
+
+    /// Refines the differing ranges of a response, returning the next round's request,
+    /// or `None` once every differing range is narrowed down
+    pub fn refine(&mut self, response: HashResponse) -> Option<HashRequest> {
+        let mut request = HashMap::new();
+        for (peer_id, ranges) in response.0 {
+            let Some((index, _)) = self.peers.get(&peer_id) else {
+                continue;
+            };
+            let mut next = Vec::new();
+            for range in ranges {
+                if range.level == 0 || !range.is_valid() {
+                    continue;
+                }
+                if range.level == MAX_LEVEL || range_len(index, range) <= LEAF_SIZE {
+                    self.regions.entry(peer_id.clone()).or_default().push(range);
+                } else {
+                    next.push(RangeHashes {
+                        range,
+                        children: child_hashes(index, range),
+                    });
+                }
+            }
+            if !next.is_empty() {
+                request.insert(peer_id, next);
+            }
+        }
+        (!request.is_empty()).then_some(HashRequest(request))
+    }
+
+    /// Returns the diff request for the differing ranges.
+    /// Every local peer is included, so that the responder only sends
+    /// its full state for peers the requester does not know.
+    pub fn finish(mut self) -> RegionDiffRequest {
+        let request = self
+            .peers
+            .iter()
+            .map(|(peer_id, (index, bookmark))| {
+                let regions = self.regions.entry((*peer_id).clone()).or_default();
+                let state = DiffRequestPeerState {
+                    index: RequestIndex::Bitmap(restrict(index, regions)),
+                    bookmark: *bookmark,
+                };
+                ((*peer_id).clone(), state)
+            })
+            .collect();
+        RegionDiffRequest {
+            request: DiffRequest(request),
+            regions: self.regions,
+        }
+    }
+}
+
+/// Lists the ranges of a hash request whose hashes differ from the local index.
+/// Peers unknown locally are left out, since the diff would not carry them anyway.
+#[cfg(feature = "memory")]
+pub(crate) fn compare_hashes<'a>(
+    request: &HashRequest,
+    index: impl Fn(&PeerId) -> Option<&'a RoaringTreemap>,
+) -> HashResponse {
+    let response: Result<_, Infallible> = try_compare_hashes(request, index, |index, range| {
+        Ok(child_hashes(index, range))
+    });
+    response.unwrap_or_else(|never| match never {})
+}
+
+/// Lists the ranges of a hash request whose hashes differ from the local state,
+/// hashing sub-ranges with `child_hashes` for each peer that `peer` finds locally
+pub(crate) fn try_compare_hashes<P, E>(
+    request: &HashRequest,
+    peer: impl Fn(&PeerId) -> Option<P>,
+    mut child_hashes: impl FnMut(&P, HlcRange) -> Result<Vec<(u16, u64)>, E>,
+) -> Result<HashResponse, E> {
+    let mut response = HashMap::new();
+    for (peer_id, hashes) in &request.0 {
+        let Some(peer) = peer(peer_id) else {
+            continue;
+        };
+        let mut differing = Vec::new();
+        for hashes in hashes {
+            if hashes.range.level >= MAX_LEVEL || !hashes.range.is_valid() {
+                continue;
+            }
+            let mut children: BTreeMap<u16, (u64, u64)> = BTreeMap::new();
+            for (digit, hash) in &hashes.children {
+                children.entry(*digit).or_default().0 = *hash;
+            }
+            for (digit, hash) in child_hashes(&peer, hashes.range)? {
+                children.entry(digit).or_default().1 = hash;
+            }
+            differing.extend(
+                children
+                    .into_iter()
+                    .filter(|(_, (remote, local))| remote != local)
+                    .map(|(digit, _)| hashes.range.child(digit)),
+            );
+        }
+        if !differing.is_empty() {
+            response.insert(peer_id.clone(), differing);
+        }
+    }
+    Ok(HashResponse(response))
+}
+
+/// Hashes the non-empty sub-ranges of a range from the `(prefix, hash)` pairs of the
+/// roaring containers within it, in prefix order.
+/// Returns `None` if the sub-ranges are narrower than a container.
+#[cfg(feature = "kv")]
+pub(crate) fn container_child_hashes(
+    range: HlcRange,
+    containers: impl IntoIterator<Item = (u64, u64)>,
+) -> Option<Vec<(u16, u64)>> {
+    let shift = range.child(0).shift().checked_sub(CONTAINER_SHIFT)?;
+    let mut children: Vec<(u16, u64)> = Vec::new();
+    for (container, container_hash) in containers {
+        let digit = (container >> shift) as u16;
+        match children.last_mut() {
+            Some((last, hash)) if *last == digit => *hash = hash.wrapping_add(container_hash),
+            _ => children.push((digit, container_hash)),
+        }
+    }
+    Some(children)
+}
+
+/// Hashes a set of HLCs the way range hashes sum them
+#[cfg(feature = "kv")]
+pub(crate) fn hash_hlcs(hlcs: impl IntoIterator<Item = u64>) -> u64 {
+    hlcs.into_iter()
+        .fold(0, |hash, hlc| hash.wrapping_add(mix(hlc)))
+}
+
+/// Returns the HLCs within the ranges
+pub(crate) fn restrict(index: &RoaringTreemap, ranges: &[HlcRange]) -> RoaringTreemap {
+    let mut restricted = RoaringTreemap::new();
+    for range in ranges {
+        restricted.extend(iter_range(index, *range));
+    }
+    restricted
+}
+
+// Hashes the non-empty sub-ranges of a range
+pub(crate) fn child_hashes(index: &RoaringTreemap, range: HlcRange) -> Vec<(u16, u64)> {
+    let shift = range.child(0).shift();
+    let mut children: Vec<(u16, u64)> = Vec::new();
+    for hlc in iter_range(index, range) {
+        let digit = (hlc >> shift) as u16;
+        match children.last_mut() {
+            Some((last, hash)) if *last == digit => *hash = hash.wrapping_add(mix(hlc)),
+            _ => children.push((digit, mix(hlc))),
+        }
+    }
+    children
+}
+
+fn iter_range(index: &RoaringTreemap, range: HlcRange) -> impl Iterator<Item = u64> {
+    let end = range.end();
+    let mut iter = index.iter();
+    iter.advance_to(range.start());
+    iter.take_while(move |hlc| *hlc <= end)
+}
+
+fn range_len(index: &RoaringTreemap, range: HlcRange) -> u64 {
+    let below = range
+        .start()
+        .checked_sub(1)
+        .map_or(0, |start| index.rank(start));
+    index.rank(range.end()) - below
+}
+
+// SplitMix64 finalizer, so that range hashes are sums of well-distributed values
+fn mix(hlc: u64) -> u64 {
+    let mut z = hlc.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ranges() {
+        assert_eq!(HlcRange::ROOT.start(), 0);
+        assert_eq!(HlcRange::ROOT.end(), u64::MAX);
+
+        let range = HlcRange::ROOT.child(1).child(2);
+        assert_eq!(range.start(), 0x0001_0002_0000_0000);
+        assert_eq!(range.end(), 0x0001_0002_ffff_ffff);
+        assert!(range.is_valid());
+        assert!(
+            !HlcRange {
+                level: 1,
+                prefix: 1 << 16
+            }
+            .is_valid()
+        );
+
+        let container = range.child(3);
+        let index = RoaringTreemap::from_iter([1, 0x0001_0002_0003_0000, 0x0001_0002_0003_ffff]);
+        assert_eq!(range_len(&index, container), 2);
+        assert_eq!(restrict(&index, &[container]).len(), 2);
+        assert_eq!(child_hashes(&index, HlcRange::ROOT).len(), 2);
+        assert_eq!(child_hashes(&index, container).len(), 2);
+
+        let leaf = container.child(0xff);
+        assert_eq!(leaf.start(), 0x0001_0002_0003_ff00);
+        assert_eq!(leaf.end(), 0x0001_0002_0003_ffff);
+        assert!(leaf.is_valid());
+    }
+}
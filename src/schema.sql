@@ -10,10 +10,13 @@ CREATE TABLE peers (
     bookmark INTEGER NOT NULL
 );
 
-CREATE TABLE bitmap_state (
-    peer_id INTEGER PRIMARY KEY AUTOINCREMENT,
-    state BLOB NOT NULL
-);
+CREATE TABLE bitmap_containers (
+    peer_id INTEGER NOT NULL,
+    container INTEGER NOT NULL,
+    state BLOB NOT NULL,
+    hash INTEGER NOT NULL,
+    PRIMARY KEY (peer_id, container)
+) WITHOUT ROWID;
 
 CREATE TABLE entries (
     key BLOB NOT NULL PRIMARY KEY,
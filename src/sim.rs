@@ -0,0 +1,368 @@
+//! Deterministic network simulator for multi-replica testing.
+//!
+//! A seeded scheduler drives local writes and sync traffic across many [`MemStore`]
+//! replicas. `DiffRequest`, `Diff` and `OpSet` messages travel through a fake network
+//! that loses, duplicates and reorders them, partitions replicas and skews their clocks.
+//! Once the network heals and goes quiet, every replica must converge.
+//! A failed run reports its seed so it can be replayed exactly.
+
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use crate::{
+    diff::{Diff, DiffRequest},
+    hlc::Hlc,
+    memory::MemStore,
+    opset::OpSet,
+};
+
+type Store = MemStore<u16, u32>;
+
+/// Simulation parameters
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    /// Number of replicas, at least two
+    pub replicas: usize,
+    /// Number of scheduler steps before the network heals
+    pub steps: usize,
+    /// Number of distinct keys written by replicas
+    pub keys: u16,
+    /// Probability that a delivered message is lost
+    pub loss: f64,
+    /// Probability that a delivered message is duplicated
+    pub duplication: f64,
+    /// Probability that the network partitions or heals at each step
+    pub partition: f64,
+    /// Maximum clock skew between replicas, in microseconds
+    pub max_clock_skew: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            replicas: 4,
+            steps: 500,
+            keys: 32,
+            loss: 0.1,
+            duplication: 0.05,
+            partition: 0.02,
+            max_clock_skew: 1_000_000,
+        }
+    }
+}
+
+/// Message counts from a simulation run
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SimStats {
+    pub sent: usize,
+    pub delivered: usize,
+    pub lost: usize,
+    pub duplicated: usize,
+    pub partitioned: usize,
+}
+
+/// Error returned when a replica breaks its invariants
+/// or fails to converge after the network quiesces
+#[derive(Debug, thiserror::Error)]
+#[error("replica {replica} {reason} (seed {seed})")]
+pub struct Divergence {
+    pub seed: u64,
+    pub replica: usize,
+    pub reason: String,
+}
+
+/// Seeded multi-replica simulation
+pub struct Simulation {
+    seed: u64,
+    config: SimConfig,
+    rng: StdRng,
+    stores: Vec<Store>,
+    skews: Vec<u64>,
+    time: u64,
+    network: Vec<Message>,
+    partition: Option<Vec<bool>>,
+    stats: SimStats,
+}
+
+struct Message {
+    from: usize,
+    to: usize,
+    payload: Payload,
+}
+
+#[derive(Clone)]
+enum Payload {
+    DiffRequest(DiffRequest),
+    Diff(Diff<u16, u32>),
+    OpSet(OpSet<u16, u32>),
+}
+
+// simulated physical time starts at a realistic epoch, in microseconds
+const START_TIME: u64 = 1_700_000_000_000_000;
+
+impl Simulation {
+    /// Creates a new simulation from a seed.
+    /// Panics if the config has fewer than two replicas, since replicas sync with each other.
+    pub fn new(seed: u64, config: SimConfig) -> Self {
+        assert!(
+            config.replicas >= 2,
+            "a simulation needs at least two replicas, got {}",
+            config.replicas
+        );
+        let mut rng = StdRng::seed_from_u64(seed);
+        let stores = (0..config.replicas)
+            .map(|i| MemStore::new(&format!("replica-{i}")).with_opset())
+            .collect();
+        let skews = (0..config.replicas)
+            .map(|_| rng.random_range(0..=config.max_clock_skew))
+            .collect();
+        Simulation {
+            seed,
+            config,
+            rng,
+            stores,
+            skews,
+            time: START_TIME,
+            network: Vec::default(),
+            partition: None,
+            stats: SimStats::default(),
+        }
+    }
+
+    /// Runs the simulation to quiescence and checks that all replicas converged
+    pub fn run(mut self) -> Result<SimStats, Divergence> {
+        for _ in 0..self.config.steps {
+            self.step();
+        }
+        self.quiesce();
+        Hlc::unset_mock_pt();
+        self.check()?;
+        Ok(self.stats)
+    }
+
+    fn step(&mut self) {
+        self.time += self.rng.random_range(0..10_000);
+        let replica = self.rng.random_range(0..self.config.replicas);
+
+        if self.rng.random_bool(self.config.partition) {
+            self.toggle_partition();
+        }
+
+        match self.rng.random_range(0..10) {
+            0..=2 => {
+                let key = self.random_key();
+                let value = self.rng.random();
+                self.set_clock(replica);
+                self.stores[replica].insert(key, value);
+            }
+            3 => {
+                let key = self.random_key();
+                self.set_clock(replica);
+                self.stores[replica].remove(&key);
+            }
+            4 => {
+                let writes: Vec<(u16, Option<u32>)> = (0..self.rng.random_range(1..8))
+                    .map(|_| {
+                        let key = self.random_key();
+                        let value = self.rng.random_bool(0.8).then(|| self.rng.random());
+                        (key, value)
+                    })
+                    .collect();
+                self.set_clock(replica);
+                let mut txn = self.stores[replica].begin();
+                for (key, value) in writes {
+                    match value {
+                        Some(value) => txn.insert(key, value),
+                        None => txn.remove(&key),
+                    }
+                }
+                txn.commit();
+            }
+            5 => {
+                // pull-based state sync: request a diff from a random peer
+                let to = self.random_peer(replica);
+                let request = self.stores[replica].request_diff();
+                self.send(replica, to, Payload::DiffRequest(request));
+            }
+            6 => {
+                // push-based op sync: broadcast the replica's opset to all peers
+                let opset = self.stores[replica].take_opset();
+                for to in 0..self.config.replicas {
+                    if to != replica {
+                        self.send(replica, to, Payload::OpSet(opset.clone()));
+                    }
+                }
+            }
+            _ => {
+                if !self.network.is_empty() {
+                    let index = self.rng.random_range(0..self.network.len());
+                    self.deliver(index, true);
+                }
+            }
+        }
+    }
+
+    // Heals the network, drains in-flight messages, then runs reliable full syncs
+    fn quiesce(&mut self) {
+        self.partition = None;
+        while !self.network.is_empty() {
+            let index = self.rng.random_range(0..self.network.len());
+            self.deliver(index, false);
+        }
+
+        for _ in 0..2 {
+            for from in 0..self.config.replicas {
+                for to in 0..self.config.replicas {
+                    if from != to {
+                        let request = self.stores[to].request_diff();
+                        let diff = self.stores[from].build_diff(request);
+                        self.stores[to].integrate_diff(diff);
+                    }
+                }
+            }
+        }
+    }
+
+    fn check(&self) -> Result<(), Divergence> {
+        let first = &self.stores[0];
+        for (replica, store) in self.stores.iter().enumerate() {
+            let report = store.check_invariants();
+            if !report.is_ok() {
+                return Err(Divergence {
+                    seed: self.seed,
+                    replica,
+                    reason: format!("broke its invariants: {report}"),
+                });
+            }
+            if first.entries() != store.entries() || first.request_diff() != store.request_diff() {
+                return Err(Divergence {
+                    seed: self.seed,
+                    replica,
+                    reason: "diverged from replica 0".to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, from: usize, to: usize, payload: Payload) {
+        self.stats.sent += 1;
+        self.network.push(Message { from, to, payload });
+    }
+
+    // Delivers a message, reordering by removing it from an arbitrary position.
+    // Faults are only injected while the network is unreliable.
+    fn deliver(&mut self, index: usize, faulty: bool) {
+        let message = self.network.swap_remove(index);
+
+        if faulty {
+            if self.is_partitioned(message.from, message.to) {
+                self.stats.partitioned += 1;
+                return;
+            }
+            if self.rng.random_bool(self.config.loss) {
+                self.stats.lost += 1;
+                return;
+            }
+            if self.rng.random_bool(self.config.duplication) {
+                self.stats.duplicated += 1;
+                self.network.push(Message {
+                    from: message.from,
+                    to: message.to,
+                    payload: message.payload.clone(),
+                });
+            }
+        }
+
+        self.stats.delivered += 1;
+        let Message { from, to, payload } = message;
+        self.set_clock(to);
+        match payload {
+            Payload::DiffRequest(request) => {
+                let diff = self.stores[to].build_diff(request);
+                self.send(to, from, Payload::Diff(diff));
+            }
+            Payload::Diff(diff) => self.stores[to].integrate_diff(diff),
+            Payload::OpSet(opset) => self.stores[to].integrate_opset(opset),
+        }
+    }
+
+    fn toggle_partition(&mut self) {
+        if self.partition.is_some() {
+            self.partition = None;
+        } else {
+            let sides = (0..self.config.replicas)
+                .map(|_| self.rng.random_bool(0.5))
+                .collect();
+            self.partition = Some(sides);
+        }
+    }
+
+    fn is_partitioned(&self, a: usize, b: usize) -> bool {
+        self.partition
+            .as_ref()
+            .is_some_and(|sides| sides[a] != sides[b])
+    }
+
+    // Sets the HLC physical time to the replica's skewed clock
+    fn set_clock(&self, replica: usize) {
+        let pt = (self.time + self.skews[replica]) & 0xFFFF_FFFF_FFFF_0000;
+        Hlc::set_mock_pt(pt);
+    }
+
+    fn random_key(&mut self) -> u16 {
+        self.rng.random_range(0..self.config.keys)
+    }
+
+    fn random_peer(&mut self, replica: usize) -> usize {
+        let peer = self.rng.random_range(0..self.config.replicas - 1);
+        if peer >= replica { peer + 1 } else { peer }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simulation_converges() {
+        for seed in 0..64 {
+            let stats = Simulation::new(seed, SimConfig::default())
+                .run()
+                .unwrap_or_else(|err| panic!("{err}"));
+            assert!(stats.delivered > 0);
+        }
+    }
+
+    #[test]
+    fn test_simulation_is_deterministic() {
+        let a = Simulation::new(7, SimConfig::default()).run().unwrap();
+        let b = Simulation::new(7, SimConfig::default()).run().unwrap();
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least two replicas")]
+    fn test_single_replica_is_rejected() {
+        let config = SimConfig {
+            replicas: 1,
+            ..SimConfig::default()
+        };
+        Simulation::new(0, config);
+    }
+
+    #[test]
+    fn test_hostile_network_converges() {
+        let config = SimConfig {
+            loss: 0.4,
+            duplication: 0.3,
+            partition: 0.1,
+            max_clock_skew: 60_000_000,
+            ..SimConfig::default()
+        };
+        for seed in 0..16 {
+            Simulation::new(seed, config.clone())
+                .run()
+                .unwrap_or_else(|err| panic!("{err}"));
+        }
+    }
+}
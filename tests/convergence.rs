@@ -0,0 +1,192 @@
+//! State sync convergence tests
+
+use cubby::memory::MemStore;
+
+type Store = MemStore<[u8; 16], [u8; 32]>;
+
+/// Syncs the full state of `from` into `to`
+fn sync(from: &Store, to: &mut Store) {
+    let request = to.request_diff();
+    let diff = from.build_diff(request);
+    to.integrate_diff(diff);
+}
+
+/// Syncs the full state of `a` and `b` in both directions
+fn sync_both(a: &mut Store, b: &mut Store) {
+    sync(b, a);
+    sync(a, b);
+}
+
+/// Asserts that both stores hold identical entries and per-peer index state
+fn assert_converged(a: &Store, b: &Store) {
+    assert!(a.check_invariants().is_ok(), "{}", a.check_invariants());
+    assert!(b.check_invariants().is_ok(), "{}", b.check_invariants());
+    assert_eq!(a.entries(), b.entries());
+    assert_eq!(a.request_diff(), b.request_diff());
+}
+
+fn random_entry() -> ([u8; 16], [u8; 32]) {
+    let mut key = [0u8; 16];
+    let mut value = [0u8; 32];
+    rand::fill(&mut key);
+    rand::fill(&mut value);
+    (key, value)
+}
+
+#[test]
+fn test_inserts_only() {
+    let mut a = Store::new("alice");
+    let mut b = Store::new("bob");
+    for _ in 0..100 {
+        let (key, value) = random_entry();
+        a.insert(key, value);
+        let (key, value) = random_entry();
+        b.insert(key, value);
+    }
+
+    sync_both(&mut a, &mut b);
+    assert_eq!(a.len(), 200);
+    assert_converged(&a, &b);
+}
+
+#[test]
+fn test_deletes_only() {
+    let mut a = Store::new("alice");
+    let mut b = Store::new("bob");
+    let keys: Vec<_> = (0..100)
+        .map(|_| {
+            let (key, value) = random_entry();
+            a.insert(key, value);
+            key
+        })
+        .collect();
+    sync_both(&mut a, &mut b);
+    assert_converged(&a, &b);
+
+    // the diff from A carries only deletes, including the delete at A's bookmark
+    for key in keys.iter().rev().step_by(2) {
+        a.remove(key);
+    }
+    sync(&a, &mut b);
+    assert_eq!(b.len(), 50);
+    assert_eq!(a.entries(), b.entries());
+
+    for key in &keys {
+        a.remove(key);
+    }
+    sync(&a, &mut b);
+    assert!(b.is_empty());
+    sync_both(&mut a, &mut b);
+    assert_converged(&a, &b);
+}
+
+#[test]
+fn test_overwrite_remote_entries() {
+    let mut a = Store::new("alice");
+    let mut b = Store::new("bob");
+    let keys: Vec<_> = (0..100)
+        .map(|_| {
+            let (key, value) = random_entry();
+            a.insert(key, value);
+            key
+        })
+        .collect();
+    sync(&a, &mut b);
+
+    for key in keys.iter().step_by(3) {
+        let (_, value) = random_entry();
+        b.insert(*key, value);
+    }
+    sync_both(&mut a, &mut b);
+    assert_eq!(a.len(), 100);
+    assert_converged(&a, &b);
+}
+
+#[test]
+fn test_concurrent_overwrites() {
+    let mut a = Store::new("alice");
+    let mut b = Store::new("bob");
+    let keys: Vec<_> = (0..100)
+        .map(|_| {
+            let (key, value) = random_entry();
+            a.insert(key, value);
+            key
+        })
+        .collect();
+    sync(&a, &mut b);
+
+    for key in &keys {
+        let (_, value) = random_entry();
+        a.insert(*key, value);
+        let (_, value) = random_entry();
+        b.insert(*key, value);
+    }
+    sync_both(&mut a, &mut b);
+    assert_eq!(a.len(), 100);
+    assert_converged(&a, &b);
+}
+
+#[test]
+fn test_three_peer_relay() {
+    let mut a = Store::new("alice");
+    let mut b = Store::new("bob");
+    let mut c = Store::new("carol");
+    let keys: Vec<_> = (0..100)
+        .map(|_| {
+            let (key, value) = random_entry();
+            a.insert(key, value);
+            key
+        })
+        .collect();
+
+    // A's entries reach C only through B
+    sync(&a, &mut b);
+    sync(&b, &mut c);
+    assert_eq!(a.entries(), c.entries());
+
+    // C's overwrites and deletes reach A only through B
+    for key in keys.iter().step_by(2) {
+        let (_, value) = random_entry();
+        c.insert(*key, value);
+    }
+    for key in keys.iter().skip(1).step_by(4) {
+        c.remove(key);
+    }
+    sync(&c, &mut b);
+    sync(&b, &mut a);
+    assert_eq!(a.len(), 75);
+    assert_eq!(a.entries(), c.entries());
+
+    sync_both(&mut a, &mut b);
+    sync_both(&mut b, &mut c);
+    assert_converged(&a, &b);
+    assert_converged(&b, &c);
+}
+
+#[test]
+fn test_repeated_syncs() {
+    let mut a = Store::new("alice");
+    let mut b = Store::new("bob");
+    for round in 0..10 {
+        for _ in 0..10 {
+            let (key, value) = random_entry();
+            a.insert(key, value);
+            let (key, value) = random_entry();
+            b.insert(key, value);
+        }
+        let keys: Vec<_> = a
+            .entries()
+            .iter()
+            .map(|(key, _)| *key)
+            .take(round)
+            .collect();
+        for key in &keys {
+            a.remove(key);
+        }
+
+        for _ in 0..3 {
+            sync_both(&mut a, &mut b);
+            assert_converged(&a, &b);
+        }
+    }
+}
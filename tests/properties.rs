@@ -0,0 +1,181 @@
+//! Property-based convergence tests.
+//! Random sequences of local writes, transactions, state syncs and op syncs are
+//! applied across several replicas, which must converge after a full sync.
+
+use cubby::memory::MemStore;
+use proptest::prelude::*;
+
+const REPLICAS: usize = 3;
+const NAMES: [&str; REPLICAS] = ["alice", "bob", "carol"];
+
+type Store = MemStore<u8, u16>;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Insert {
+        replica: usize,
+        key: u8,
+        value: u16,
+    },
+    Remove {
+        replica: usize,
+        key: u8,
+    },
+    Txn {
+        replica: usize,
+        writes: Vec<(u8, Option<u16>)>,
+        with_ops_to: Option<usize>,
+    },
+    StateSync {
+        from: usize,
+        to: usize,
+    },
+    OpsetSync {
+        from: usize,
+        to: usize,
+    },
+    HashSync {
+        from: usize,
+        to: usize,
+    },
+}
+
+fn replica() -> impl Strategy<Value = usize> {
+    0..REPLICAS
+}
+
+// a small key space forces frequent overwrites and conflicts
+fn key() -> impl Strategy<Value = u8> {
+    0u8..16
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        4 => (replica(), key(), any::<u16>())
+            .prop_map(|(replica, key, value)| Op::Insert { replica, key, value }),
+        2 => (replica(), key()).prop_map(|(replica, key)| Op::Remove { replica, key }),
+        2 => (
+            replica(),
+            prop::collection::vec((key(), prop::option::weighted(0.8, any::<u16>())), 0..8),
+            prop::option::of(replica()),
+        )
+            .prop_map(|(replica, writes, with_ops_to)| Op::Txn {
+                replica,
+                writes,
+                with_ops_to,
+            }),
+        2 => (replica(), replica()).prop_map(|(from, to)| Op::StateSync { from, to }),
+        1 => (replica(), replica()).prop_map(|(from, to)| Op::OpsetSync { from, to }),
+        1 => (replica(), replica()).prop_map(|(from, to)| Op::HashSync { from, to }),
+    ]
+}
+
+fn sync(stores: &mut [Store], from: usize, to: usize) {
+    if from == to {
+        return;
+    }
+    let diff = stores[from].build_diff(stores[to].request_diff());
+    stores[to].integrate_diff(diff);
+}
+
+// Syncs with hash-based reconciliation instead of a full index request
+fn hash_sync(stores: &mut [Store], from: usize, to: usize) {
+    if from == to {
+        return;
+    }
+    let mut reconciler = stores[to].reconciler();
+    let mut request = reconciler.request();
+    while let Some(next) = reconciler.refine(stores[from].compare_hashes(&request)) {
+        request = next;
+    }
+    let diff = stores[from].build_region_diff(reconciler.finish());
+    stores[to].integrate_diff(diff);
+}
+
+fn apply(stores: &mut [Store], op: Op) {
+    match op {
+        Op::Insert {
+            replica,
+            key,
+            value,
+        } => {
+            stores[replica].insert(key, value);
+        }
+        Op::Remove { replica, key } => {
+            stores[replica].remove(&key);
+        }
+        Op::Txn {
+            replica,
+            writes,
+            with_ops_to,
+        } => {
+            let mut txn = stores[replica].begin();
+            for (key, value) in writes {
+                match value {
+                    Some(value) => txn.insert(key, value),
+                    None => txn.remove(&key),
+                }
+            }
+            match with_ops_to {
+                Some(to) if to != replica => {
+                    let ops = txn.commit_with_ops();
+                    stores[to].integrate_opset(ops);
+                }
+                _ => txn.commit(),
+            }
+        }
+        Op::StateSync { from, to } => sync(stores, from, to),
+        Op::HashSync { from, to } => hash_sync(stores, from, to),
+        Op::OpsetSync { from, to } => {
+            let opset = stores[from].take_opset();
+            if from != to {
+                stores[to].integrate_opset(opset);
+            }
+        }
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(256))]
+
+    #[test]
+    fn test_strong_eventual_consistency(ops in prop::collection::vec(op(), 0..64)) {
+        let mut stores: Vec<Store> = NAMES
+            .iter()
+            .map(|name| MemStore::new(name).with_opset())
+            .collect();
+        for op in ops {
+            apply(&mut stores, op);
+            for store in &stores {
+                let report = store.check_invariants();
+                prop_assert!(report.is_ok(), "{}", report);
+            }
+        }
+
+        // full sync: two rounds of pairwise syncs reach every replica from every other
+        for _ in 0..2 {
+            for from in 0..REPLICAS {
+                for to in 0..REPLICAS {
+                    sync(&mut stores, from, to);
+                }
+            }
+        }
+
+        for store in &stores[1..] {
+            let report = store.check_invariants();
+            prop_assert!(report.is_ok(), "{}", report);
+            prop_assert_eq!(stores[0].entries(), store.entries());
+            prop_assert_eq!(stores[0].request_diff(), store.request_diff());
+        }
+
+        // once converged, further syncs carry no changes
+        for from in 0..REPLICAS {
+            for to in 0..REPLICAS {
+                if from != to {
+                    let diff = stores[from].build_diff(stores[to].request_diff());
+                    prop_assert!(diff.is_empty());
+                }
+            }
+        }
+    }
+}